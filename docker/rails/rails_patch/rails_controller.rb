@@ -5,6 +5,11 @@ class MonstersController < ActionController::Base
     # cookies.signed[:signed] = ENV['CANARY_VALUE']
     cookies.encrypted[:encrypted] = ENV['CANARY_VALUE']
     session[:session] = ENV['CANARY_VALUE']
-    render json: { version: Rails::VERSION::STRING }
+    render json: {
+      version: Rails::VERSION::STRING,
+      environment: Rails.env,
+      # Generated in development and test, whatever SECRET_KEY_BASE says.
+      secret_key_base: Rails.application.secret_key_base,
+    }
   end
 end
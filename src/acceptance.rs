@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+
+use chrono::{TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::profile::Profile;
+use crate::rails::encoder::CookieEncoder;
+use crate::scenarios::Jar;
+
+/// The readers of [`crate::scenarios::ACCEPT_ROUTE`], by the name it reports
+/// them under.
+pub const READERS: [&str; 3] = [
+  "signed",
+  "encrypted",
+  "session",
+];
+
+/// A cookie sent to every app (`ACCEPT_COOKIES`), to check that Rails reads it.
+///
+/// * name: The name of the cookie, the session key of the apps for the session
+/// * value: The value of the cookie, URL-decoded as in the captures
+/// * jar: The jar expected to read the cookie, signed, encrypted or session
+/// * expected: The value the jar should read, any value if unset. For the
+///   session, the entries it should hold.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SentCookie {
+  pub name: String,
+  pub value: String,
+  pub jar: Jar,
+  #[serde(default)]
+  pub expected: Option<Value>,
+}
+
+impl SentCookie {
+  /// The reader of [`READERS`] the cookie is expected to pass.
+  pub fn reader(&self) -> Option<&'static str> {
+    match self.jar {
+      Jar::Signed | Jar::PermanentSigned => Some("signed"),
+      Jar::Encrypted | Jar::PermanentEncrypted => Some("encrypted"),
+      Jar::Session => Some("session"),
+      Jar::Plain | Jar::Permanent => None,
+    }
+  }
+
+  /// Checks that the cookie can be read back, before sending it to every app.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.name.is_empty() {
+      return Err("Sent cookie without a name".to_string());
+    }
+    if self.reader().is_none() {
+      return Err(format!(
+        "Cookie {} is read from the {} jar, which accepts any value",
+        self.name,
+        self.jar.name()
+      ));
+    }
+    if self.jar == Jar::Session && !matches!(self.expected, None | Some(Value::Object(_))) {
+      return Err(format!(
+        "Cookie {} expects session entries that are not an object",
+        self.name
+      ));
+    }
+    Ok(())
+  }
+
+  /// Whether Rails accepted the cookie, given what each reader `read`: its
+  /// jar read a value, or a session with entries besides `session_id`.
+  ///
+  /// The cookie store reports a `session_id` even when it drops the session
+  /// cookie, as it starts a new session.
+  ///
+  /// # Examples
+  /// ```
+  /// use rails_cookies_monster::acceptance::SentCookie;
+  /// use rails_cookies_monster::scenarios::Jar;
+  /// use serde_json::json;
+  ///
+  /// let cookie = SentCookie {
+  ///   name: "_cookie_monster_session".to_string(),
+  ///   value: "forged".to_string(),
+  ///   jar: Jar::Session,
+  ///   expected: Some(json!({ "canary": "correct-horse-battery-staple" })),
+  /// };
+  /// let read = json!({ "signed": null, "encrypted": null, "session": { "session_id": "3c4d" } });
+  /// assert!(!cookie.accepted(read.as_object().unwrap()));
+  /// assert!(!cookie.passed(read.as_object().unwrap()));
+  ///
+  /// let session = json!({ "session_id": "1a2b", "canary": "correct-horse-battery-staple" });
+  /// let read = json!({ "signed": null, "encrypted": { "canary": "-" }, "session": session });
+  /// assert!(cookie.accepted(read.as_object().unwrap()));
+  /// assert!(cookie.passed(read.as_object().unwrap()));
+  ///
+  /// let session = json!({ "session_id": "1a2b", "canary": "tampered" });
+  /// let read = json!({ "signed": null, "encrypted": null, "session": session });
+  /// assert!(cookie.accepted(read.as_object().unwrap()));
+  /// assert!(!cookie.passed(read.as_object().unwrap()));
+  /// ```
+  pub fn accepted(&self, read: &serde_json::Map<String, Value>) -> bool {
+    match self.reader().and_then(|reader| read.get(reader)) {
+      Some(Value::Null) | None => false,
+      Some(Value::Object(session)) if self.jar == Jar::Session => {
+        session.keys().any(|key| key != "session_id")
+      }
+      Some(_) => true,
+    }
+  }
+
+  /// Whether Rails accepted the cookie and read the expected value from it:
+  /// for the session, the expected entries, `session_id` aside.
+  pub fn passed(&self, read: &serde_json::Map<String, Value>) -> bool {
+    let value = self.reader().and_then(|reader| read.get(reader));
+    self.accepted(read)
+      && match (&self.expected, value) {
+        (None, _) => true,
+        (Some(Value::Object(entries)), Some(Value::Object(session)))
+          if self.jar == Jar::Session =>
+        {
+          entries
+            .iter()
+            .filter(|(key, _)| *key != "session_id")
+            .all(|(key, value)| session.get(key) == Some(value))
+        }
+        (Some(expected), value) => value == Some(expected),
+      }
+  }
+}
+
+/// Loads the cookies to send from a JSON file holding a list of [`SentCookie`].
+pub fn load(path: &str) -> Result<Vec<SentCookie>, String> {
+  let contents = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+  let cookies: Vec<SentCookie> =
+    serde_json::from_str(&contents).map_err(|err| format!("{}: {}", path, err))?;
+  for cookie in &cookies {
+    cookie.validate()?;
+  }
+  Ok(cookies)
+}
+
+/// The cookies of the encoder suite (`ENCODER_SUITE`), written by `encoder`
+/// as its app would write them, holding the canary.
+///
+/// * encoded-signed: A signed cookie
+/// * encoded-encrypted: An encrypted cookie, with the cipher of the app
+/// * encoded-expiring: An encrypted cookie expiring in an hour
+/// * encoded-typed: An encrypted cookie holding every JSON type
+/// * the session key: The session, holding the canary under `canary`
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::acceptance;
+/// use rails_cookies_monster::rails::decipher_envelope;
+/// use rails_cookies_monster::rails::encoder::CookieEncoder;
+///
+/// let encoder = CookieEncoder::new("8.0.1", "rails-cookies-everywhere");
+/// let cookies = acceptance::encoded_cookies(&encoder, "canary", "_app_session").unwrap();
+/// let session = cookies.iter().find(|cookie| cookie.name == "_app_session").unwrap();
+/// let envelope = decipher_envelope(
+///   encoder.serializer,
+///   encoder.cipher,
+///   encoder.key_generator,
+///   &encoder.salts,
+///   &encoder.secret_key_base,
+///   &session.value,
+/// )
+/// .unwrap();
+/// assert_eq!(envelope.message["canary"], "canary");
+/// assert_eq!(envelope.purpose.as_deref(), Some("cookie._app_session"));
+/// ```
+pub fn encoded_cookies(
+  encoder: &CookieEncoder,
+  canary: &str,
+  session_key: &str,
+) -> Result<Vec<SentCookie>, String> {
+  let expires_at = Utc::now() + TimeDelta::hours(1);
+  let typed = json!({
+    "string": canary,
+    "unicode": "Cookie Monster 🍪 ñam",
+    "integer": 42,
+    "negative": -1,
+    "float": 1.5,
+    "boolean": true,
+    "nil": null,
+    "array": [1, "two", [3]],
+  });
+  let encrypted = |name: &str, message: &Value, expires_at| {
+    let envelope = encoder.envelope(name, message.clone(), expires_at);
+    Ok::<_, String>(SentCookie {
+      name: name.to_string(),
+      value: encoder.encrypted(&envelope)?,
+      jar: Jar::Encrypted,
+      expected: Some(message.clone()),
+    })
+  };
+  let signed = encoder.envelope("encoded-signed", canary.into(), None);
+  let session = json!({ "canary": canary });
+  Ok(vec![
+    SentCookie {
+      name: "encoded-signed".to_string(),
+      value: encoder.signed(&signed),
+      jar: Jar::Signed,
+      expected: Some(canary.into()),
+    },
+    encrypted("encoded-encrypted", &canary.into(), None)?,
+    encrypted("encoded-expiring", &canary.into(), Some(expires_at))?,
+    encrypted("encoded-typed", &typed, None)?,
+    SentCookie {
+      name: session_key.to_string(),
+      value: encoder.session(session_key, session.as_object().unwrap())?,
+      jar: Jar::Session,
+      expected: Some(session),
+    },
+  ])
+}
+
+/// A cookie sent to a Rails version, and what Rails read from it.
+///
+/// * rails_version: The Rails version requested
+/// * profile: The configuration the Rails app was booted with
+/// * cookie: The cookie sent
+/// * read: The value read through each of [`READERS`], `null` when rejected
+/// * accepted: Whether the jar of the cookie read it
+/// * passed: Whether the jar read the expected value
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AcceptanceCapture {
+  pub rails_version: String,
+  pub profile: Profile,
+  pub cookie: SentCookie,
+  pub read: BTreeMap<String, Value>,
+  pub accepted: bool,
+  pub passed: bool,
+}
@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use log::{trace, warn};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::runtime::ContainerRuntime;
+use crate::RailsCookiesMonster;
+
+/// The IDs of the containers a run has started and not removed yet.
+///
+/// Cloning the registry shares it, so the signal handler sees the containers
+/// started after it was installed.
+#[derive(Clone, Debug, Default)]
+pub struct ContainerRegistry(Arc<Mutex<HashSet<String>>>);
+
+impl ContainerRegistry {
+  pub fn track(&self, container_id: &str) {
+    self.lock().insert(container_id.to_owned());
+  }
+
+  pub fn untrack(&self, container_id: &str) {
+    self.lock().remove(container_id);
+  }
+
+  /// Empties the registry, returning the containers it was tracking.
+  pub fn take(&self) -> Vec<String> {
+    self.lock().drain().collect()
+  }
+
+  // A panic while holding the lock must not prevent the cleanup.
+  fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+    self
+      .0
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+/// Removes the containers still tracked by its registry when dropped.
+///
+/// Owned by the [`RailsCookiesMonster`] of a run, so that containers do not
+/// outlive it on panics and early returns.
+pub struct CleanupGuard {
+  pub registry: ContainerRegistry,
+  runtime: Arc<dyn ContainerRuntime>,
+}
+
+impl CleanupGuard {
+  pub fn new(runtime: Arc<dyn ContainerRuntime>) -> Self {
+    Self {
+      registry: ContainerRegistry::default(),
+      runtime,
+    }
+  }
+}
+
+impl Drop for CleanupGuard {
+  fn drop(&mut self) {
+    let containers = self.registry.take();
+    if containers.is_empty() {
+      return;
+    }
+
+    warn!("Cleaning up {} leftover containers", containers.len());
+    let cleanup = RailsCookiesMonster::drop_containers(self.runtime.clone(), containers);
+    let runtime = || tokio::runtime::Runtime::new().expect("Could not start a runtime for cleanup");
+    match Handle::try_current() {
+      Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+        tokio::task::block_in_place(|| handle.block_on(cleanup))
+      }
+      // Blocking a current thread runtime panics, and this drop keeps it from
+      // running the cleanup: another thread runs it on a runtime of its own.
+      Ok(_) => {
+        let cleanup = std::thread::spawn(move || runtime().block_on(cleanup));
+        if cleanup.join().is_err() {
+          warn!("Could not clean up the leftover containers");
+        }
+      }
+      Err(_) => runtime().block_on(cleanup),
+    }
+  }
+}
+
+/// Whether the process `pid` is still running, e.g. the run owning a container.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::cleanup::process_alive;
+///
+/// assert!(process_alive(std::process::id()));
+/// assert!(!process_alive(u32::MAX));
+/// ```
+pub fn process_alive(pid: u32) -> bool {
+  let procfs = std::path::Path::new("/proc");
+  if procfs.is_dir() {
+    return procfs.join(pid.to_string()).exists();
+  }
+  std::process::Command::new("ps")
+    .args([
+      "-p",
+      &pid.to_string(),
+    ])
+    .stdout(std::process::Stdio::null())
+    .status()
+    .is_ok_and(|status| status.success())
+}
+
+/// Removes the tracked containers and exits once SIGINT or SIGTERM is received.
+pub fn spawn_signal_handler(registry: ContainerRegistry, runtime: Arc<dyn ContainerRuntime>) {
+  tokio::spawn(async move {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen to SIGTERM");
+    let exit_code = tokio::select! {
+      _ = tokio::signal::ctrl_c() => 130,
+      _ = sigterm.recv() => 143,
+    };
+    trace!("Received termination signal");
+
+    let containers = registry.take();
+    if !containers.is_empty() {
+      warn!("Interrupted, removing {} containers", containers.len());
+      RailsCookiesMonster::drop_containers(runtime, containers).await;
+    }
+    std::process::exit(exit_code);
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::sync::Arc;
+
+  use super::CleanupGuard;
+  use crate::runtime::fake::FakeRuntime;
+  use crate::runtime::{ContainerRuntime, ContainerSpec, ImageBuild, RUN_LABEL};
+
+  /// A guard tracking a running container of `runtime`.
+  async fn guard(runtime: &Arc<FakeRuntime>) -> CleanupGuard {
+    let image = "rails-cookies-everywhere:rails-v8.0.1".to_string();
+    let build = ImageBuild {
+      tag: image.clone(),
+      context: Default::default(),
+      args: HashMap::new(),
+    };
+    runtime.build_image(&build).await.unwrap();
+    let spec = ContainerSpec {
+      image,
+      labels: HashMap::from([(RUN_LABEL.to_string(), std::process::id().to_string())]),
+      ..ContainerSpec::default()
+    };
+    let id = runtime.create_container(&spec).await.unwrap();
+    runtime.start_container(&id).await.unwrap();
+    let guard = CleanupGuard::new(runtime.clone());
+    guard.registry.track(&id);
+    guard
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn drop_on_multi_thread_runtime() {
+    let runtime = Arc::new(FakeRuntime::new());
+    drop(guard(&runtime).await);
+    assert!(runtime
+      .labelled_containers(RUN_LABEL)
+      .await
+      .unwrap()
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn drop_on_current_thread_runtime() {
+    let runtime = Arc::new(FakeRuntime::new());
+    drop(guard(&runtime).await);
+    assert!(runtime
+      .labelled_containers(RUN_LABEL)
+      .await
+      .unwrap()
+      .is_empty());
+  }
+
+  #[test]
+  fn drop_without_runtime() {
+    let runtime = Arc::new(FakeRuntime::new());
+    let guard = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(guard(&runtime));
+    drop(guard);
+    let containers = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runtime.labelled_containers(RUN_LABEL));
+    assert!(containers.unwrap().is_empty());
+  }
+}
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::rotation::RotationFlow;
+use crate::scenarios::Scenario;
+use crate::RailsContainer;
+
+/// Label set on the containers kept running by `serve`, instead of the run label.
+pub const DAEMON_LABEL: &str = "rails-cookies-monster.daemon";
+
+/// What `serve` leaves behind for later invocations to find its containers.
+///
+/// * secret: The `SECRET_KEY_BASE` the containers were started with
+/// * canary: The `CANARY_VALUE` the containers were started with
+/// * scenarios: The scenarios the containers were started with
+/// * rotations: The rotation flows the containers were started for
+/// * containers: The running containers
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DaemonState {
+  pub secret: String,
+  pub canary: String,
+  pub scenarios: Vec<Scenario>,
+  #[serde(default)]
+  pub rotations: Vec<RotationFlow>,
+  pub containers: Vec<RailsContainer>,
+}
+
+/// Where the state is persisted.
+///
+/// Read from `DAEMON_STATE_FILE`, defaults to `rails-cookies-monster/daemon.json`
+/// in the temporary directory.
+pub fn state_path() -> PathBuf {
+  match std::env::var("DAEMON_STATE_FILE") {
+    Ok(value) => PathBuf::from(value),
+    Err(_) => std::env::temp_dir()
+      .join("rails-cookies-monster")
+      .join("daemon.json"),
+  }
+}
+
+pub fn load() -> Result<DaemonState, String> {
+  let path = state_path();
+  let contents = std::fs::read_to_string(&path)
+    .map_err(|err| format!("No daemon state at {}: {}", path.display(), err))?;
+  serde_json::from_str(&contents).map_err(|err| err.to_string())
+}
+
+pub fn save(state: &DaemonState) -> Result<(), String> {
+  let path = state_path();
+  if let Some(directory) = path.parent() {
+    std::fs::create_dir_all(directory).map_err(|err| err.to_string())?;
+  }
+  let contents = serde_json::to_string_pretty(state).map_err(|err| err.to_string())?;
+  std::fs::write(&path, contents).map_err(|err| err.to_string())?;
+  debug!("Saved daemon state to {}", path.display());
+  Ok(())
+}
+
+pub fn clear() -> Result<(), String> {
+  let path = state_path();
+  if !path.exists() {
+    return Ok(());
+  }
+  std::fs::remove_file(&path).map_err(|err| err.to_string())
+}
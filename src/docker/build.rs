@@ -7,14 +7,22 @@ use std::path::Path;
 use log::trace;
 
 use super::DOCKER;
+use crate::runtime::ImageBuild;
 
-pub(crate) async fn build(options: ContainerBuildOptions, tar_file: &Path) -> Result<(), String> {
+pub(crate) async fn build(image: &ImageBuild) -> Result<(), String> {
+  let options = ContainerBuildOptions {
+    dockerfile: "Dockerfile".into(),
+    t: vec![image.tag.clone()],
+    buildargs: Some(image.args.clone()),
+    q: std::env::var("DEBUG_DOCKER_LOGS").is_err(),
+    ..ContainerBuildOptions::default()
+  };
   let mut stream = DOCKER
     .lock()
     .await
-    .build_image(options, tar_file)
+    .build_image(options, &image.context)
     .await
-    .unwrap();
+    .map_err(|err| err.to_string())?;
 
   while let Some(Ok(msg)) = stream.next().await {
     if std::env::var("DEBUG_DOCKER_LOGS").is_ok() {
@@ -28,41 +36,29 @@ pub(crate) async fn build(options: ContainerBuildOptions, tar_file: &Path) -> Re
 }
 
 // // static DOCKER_BASE: &[u8] = include_bytes!("../../ruby-base.tar");
-pub async fn base(base: &str) -> Result<(), String> {
+pub fn base(base: &str) -> ImageBuild {
   let args = [("BASE_IMAGE_TAG".to_owned(), base.to_owned())];
-  let options = ContainerBuildOptions {
-    dockerfile: "Dockerfile".into(),
-    t: vec![format!(
-      "rails-cookies-everywhere:ruby-base-{}",
-      base
-    )],
-    buildargs: Some(HashMap::from(args)),
-    q: std::env::var("DEBUG_DOCKER_LOGS").is_err(),
-    ..ContainerBuildOptions::default()
-  };
   let cargo_path = &std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_PATH not set");
   let cwd = Path::new(cargo_path);
-  build(options, &cwd.join("ruby-base.tar")).await
+  ImageBuild {
+    tag: format!("rails-cookies-everywhere:ruby-base-{}", base),
+    context: cwd.join("ruby-base.tar"),
+    args: HashMap::from(args),
+  }
 }
 
 // // static DOCKER_VERSION: &[u8] = include_bytes!("../../rails-version.tar");
-pub async fn version(base: &str, version: &str, patch: &str) -> Result<(), String> {
+pub fn version(base: &str, version: &str, patch: &str) -> ImageBuild {
   let args = [
     ("BASE_IMAGE_TAG".to_owned(), base.to_owned()),
     ("RAILS_VERSION_TAG".to_owned(), version.to_owned()),
     ("RAILS_PATCH".to_owned(), patch.to_owned()),
   ];
-  let options = ContainerBuildOptions {
-    dockerfile: "Dockerfile".into(),
-    t: vec![format!(
-      "rails-cookies-everywhere:rails-v{}",
-      version
-    )],
-    buildargs: Some(HashMap::from(args)),
-    q: std::env::var("DEBUG_DOCKER_LOGS").is_err(),
-    ..ContainerBuildOptions::default()
-  };
   let cargo_path = &std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_PATH not set");
   let cwd = Path::new(cargo_path);
-  build(options, &cwd.join("rails-versions.tar")).await
+  ImageBuild {
+    tag: format!("rails-cookies-everywhere:rails-v{}", version),
+    context: cwd.join("rails-versions.tar"),
+    args: HashMap::from(args),
+  }
 }
@@ -1,53 +1,264 @@
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
-use std::sync::OnceLock;
 
+use async_trait::async_trait;
+use dockworker::container::ContainerFilters;
+use dockworker::network::{ListNetworkFilters, NetworkCreateOptions};
+use dockworker::ContainerCreateOptions;
+use dockworker::ContainerHostConfig;
+use dockworker::ContainerLogOptions;
 use dockworker::Docker;
+use dockworker::ExposedPorts;
+use futures::stream::StreamExt;
 use lazy_static::lazy_static;
-use log::error;
 use tokio::sync::Mutex;
 
-pub(crate) mod build;
+use crate::runtime::{ContainerRuntime, ContainerSpec, ContainerState, ImageBuild};
 
-pub(crate) static IMAGES: OnceLock<HashSet<String>> = OnceLock::new();
+pub(crate) mod build;
 
 lazy_static! {
   pub(crate) static ref DOCKER: Arc<Mutex<Docker>> =
     Arc::new(Mutex::new(Docker::connect_with_defaults().unwrap()));
 }
 
-pub(crate) async fn cache_images() {
-  let mut images = list_images().await;
-  images.sort();
-  let Ok(_) = IMAGES.set(images.into_iter().collect()) else {
-    error!("Error: Failed to cache available Docker images");
-    return;
-  };
-}
+/// The CFS scheduler period, in microseconds, the CPU quota is relative to.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Where the app lives in the Rails images.
+const APP_DIRECTORY: &str = "/app/cookie-monster";
+
+/// Copies files into the app of a created container.
+///
+/// The archive endpoint only takes a tar file, which is written to the
+/// temporary directory for the upload.
+async fn upload_files(container_id: &str, files: &[(String, String)]) -> Result<(), String> {
+  let mut archive = tar::Builder::new(vec![]);
+  for (path, contents) in files {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    archive
+      .append_data(&mut header, path, contents.as_bytes())
+      .map_err(|err| err.to_string())?;
+  }
+  let archive = archive.into_inner().map_err(|err| err.to_string())?;
+  let archive_path =
+    std::env::temp_dir().join(format!("rails-cookies-monster-{}.tar", container_id));
+  std::fs::write(&archive_path, archive).map_err(|err| err.to_string())?;
 
-async fn list_images() -> Vec<String> {
-  DOCKER
+  let uploaded = DOCKER
     .lock()
     .await
-    .images(true)
+    .put_file(container_id, &archive_path, Path::new(APP_DIRECTORY), true)
     .await
-    .unwrap()
-    .iter()
-    .filter(|image| {
-      image
-        .RepoTags
+    .map_err(|err| err.to_string());
+  let _ = std::fs::remove_file(&archive_path);
+  uploaded
+}
+
+/// The runtime backed by the local Docker daemon.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DockerRuntime;
+
+#[async_trait]
+impl ContainerRuntime for DockerRuntime {
+  async fn list_images(&self) -> Result<Vec<String>, String> {
+    let images = DOCKER
+      .lock()
+      .await
+      .images(true)
+      .await
+      .map_err(|err| err.to_string())?;
+    Ok(
+      images
         .iter()
-        .any(|tag| tag.starts_with("rails-cookies-everywhere:"))
+        .filter(|image| {
+          image
+            .RepoTags
+            .iter()
+            .any(|tag| tag.starts_with("rails-cookies-everywhere:"))
+        })
+        .flat_map(|image| image.RepoTags.iter().cloned())
+        .collect(),
+    )
+  }
+
+  async fn build_image(&self, image: &ImageBuild) -> Result<(), String> {
+    build::build(image).await
+  }
+
+  async fn create_container(&self, spec: &ContainerSpec) -> Result<String, String> {
+    let mut host_config = ContainerHostConfig::new();
+    match &spec.network {
+      // Ports cannot be published from an internal network, the container is
+      // reached on its address in the network instead.
+      Some(network) => host_config.network_mode(network.clone()),
+      // Let the daemon pick a free host port, it is read back on inspection.
+      None => host_config.publish_all_ports(true),
+    };
+    if let Some(memory) = spec.memory {
+      // Without a swap cap, the container could swap as much again.
+      host_config.memory(memory).memory_swap(memory);
+    }
+    if let Some(cpus) = spec.cpus {
+      host_config
+        .cpu_period(CPU_PERIOD)
+        .cpu_quota((cpus * CPU_PERIOD as f64) as u64);
+    }
+    let mut options = ContainerCreateOptions::new(&spec.image);
+    for env in &spec.env {
+      options.env(env.clone());
+    }
+    for (key, value) in &spec.labels {
+      options.label(key.clone(), value.clone());
+    }
+    options
+      .exposed_ports(ExposedPorts(vec![(3000, "tcp".to_string())]))
+      // Without a TTY, logs come multiplexed and cannot be read line by line.
+      .tty(true)
+      .host_config(host_config);
+
+    let container = DOCKER
+      .lock()
+      .await
+      .create_container(Some(&spec.name), &options)
+      .await
+      .map_err(|err| err.to_string())?;
+    if !spec.files.is_empty() {
+      upload_files(&container.id, &spec.files).await?;
+    }
+    Ok(container.id)
+  }
+
+  async fn start_container(&self, container_id: &str) -> Result<(), String> {
+    DOCKER
+      .lock()
+      .await
+      .start_container(container_id)
+      .await
+      .map_err(|err| err.to_string())
+  }
+
+  async fn inspect_container(&self, container_id: &str) -> Result<ContainerState, String> {
+    let info = DOCKER
+      .lock()
+      .await
+      .container_info(container_id)
+      .await
+      .map_err(|err| err.to_string())?;
+    // Ports are allocated by the daemon when the container starts.
+    let published = info
+      .NetworkSettings
+      .Ports
+      .get("3000/tcp")
+      .and_then(|mappings| mappings.as_ref())
+      .and_then(|mappings| mappings.first())
+      .map(|mapping| format!("localhost:{}", mapping.HostPort));
+    // Containers on an internal network only have their address in it.
+    let address = published.or_else(|| {
+      info
+        .NetworkSettings
+        .Networks
+        .values()
+        .find(|network| !network.IPAddress.is_empty())
+        .map(|network| format!("{}:3000", network.IPAddress))
+    });
+    Ok(ContainerState {
+      running: info.State.Running,
+      exit_code: info.State.ExitCode,
+      address,
     })
-    .flat_map(|image| image.RepoTags.iter().cloned())
-    .collect()
-}
+  }
+
+  async fn container_logs(&self, container_id: &str) -> Result<Vec<String>, String> {
+    let mut stream = DOCKER
+      .lock()
+      .await
+      .log_container(container_id, &ContainerLogOptions::default())
+      .await
+      .map_err(|err| err.to_string())?;
+
+    let mut lines = vec![];
+    while let Some(Ok(line)) = stream.next().await {
+      lines.push(line);
+    }
+    Ok(lines)
+  }
+
+  async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+    DOCKER
+      .lock()
+      .await
+      .remove_container(container_id, Some(true), Some(true), None)
+      .await
+      .map_err(|err| err.to_string())
+  }
+
+  async fn labelled_containers(&self, label: &str) -> Result<Vec<(String, String)>, String> {
+    let containers = DOCKER
+      .lock()
+      .await
+      .list_containers(Some(true), None, None, ContainerFilters::new())
+      .await
+      .map_err(|err| err.to_string())?;
+    Ok(
+      containers
+        .into_iter()
+        .filter_map(|container| {
+          let value = container.Labels.as_ref()?.get(label)?.clone();
+          Some((container.Id, value))
+        })
+        .collect(),
+    )
+  }
+
+  async fn create_network(
+    &self,
+    name: &str,
+    labels: &HashMap<String, String>,
+  ) -> Result<(), String> {
+    let docker = DOCKER.lock().await;
+    if docker.inspect_network(name, None, None).await.is_ok() {
+      return Ok(());
+    }
+    let mut options = NetworkCreateOptions::new(name);
+    options.internal = true;
+    options.labels.extend(labels.clone());
+    docker
+      .create_network(&options)
+      .await
+      .map(|_| ())
+      .map_err(|err| err.to_string())
+  }
+
+  async fn labelled_networks(&self, label: &str) -> Result<Vec<(String, String)>, String> {
+    let mut filters = ListNetworkFilters::default();
+    filters.label(label.into());
+    let networks = DOCKER
+      .lock()
+      .await
+      .list_networks(filters)
+      .await
+      .map_err(|err| err.to_string())?;
+    Ok(
+      networks
+        .into_iter()
+        .filter_map(|network| {
+          let value = network.Labels.get(label)?.clone();
+          Some((network.Name, value))
+        })
+        .collect(),
+    )
+  }
 
-pub(crate) fn image_exists(image_tag: &str) -> bool {
-  let image_full_tag = if image_tag.starts_with("rails-cookies-everywhere:") {
-    image_tag.to_string()
-  } else {
-    format!("rails-cookies-everywhere:{}", image_tag)
-  };
-  IMAGES.get().unwrap().contains(&image_full_tag)
+  async fn remove_network(&self, name: &str) -> Result<(), String> {
+    DOCKER
+      .lock()
+      .await
+      .remove_network(name)
+      .await
+      .map_err(|err| err.to_string())
+  }
 }
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use urlencoding::encode;
+
+use crate::profile::{Profile, SessionStore};
+use crate::scenarios::ruby_string;
+use crate::CapturedCookie;
+
+/// Where the generated flows controller is written, relative to the app root.
+pub const CONTROLLER_PATH: &str = "app/controllers/flows_controller.rb";
+
+/// A request of the session flow (`SESSION_FLOW_SUITE`), sent with the
+/// cookies set by the previous ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowStep {
+  /// Stores the canary in a new session
+  Create,
+  /// Changes the value in the session, which is encrypted again
+  Mutate,
+  /// Calls `reset_session`, starting a new session
+  Reset,
+  /// Deletes the session cookie
+  Delete,
+}
+
+impl FlowStep {
+  /// The steps in the order they are requested.
+  pub const ALL: [FlowStep; 4] = [
+    FlowStep::Create,
+    FlowStep::Mutate,
+    FlowStep::Reset,
+    FlowStep::Delete,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      FlowStep::Create => "create",
+      FlowStep::Mutate => "mutate",
+      FlowStep::Reset => "reset",
+      FlowStep::Delete => "delete",
+    }
+  }
+
+  /// The path of the route of the step.
+  pub fn route(&self) -> String {
+    format!("/flows/session/{}", self.name())
+  }
+
+  /// The controller action of the step.
+  fn action(&self) -> String {
+    format!("session_{}", self.name())
+  }
+
+  /// The Ruby statements of the step, for an app storing its session as `session`.
+  ///
+  /// The session is not read when deleting its cookie, or Rails would set it again.
+  fn statements(&self, session: &SessionStore) -> String {
+    match self {
+      FlowStep::Create => "session[:flow] = ENV[\"CANARY_VALUE\"]".to_string(),
+      FlowStep::Mutate => "session[:flow] = \"#{session[:flow]}-mutated\"".to_string(),
+      FlowStep::Reset => "reset_session".to_string(),
+      FlowStep::Delete => {
+        let mut options = vec![];
+        if let Some(path) = &session.path {
+          options.push(format!(", path: {}", ruby_string(path)));
+        }
+        match session.domain.as_deref() {
+          Some("all") => options.push(", domain: :all".to_string()),
+          Some(domain) => options.push(format!(", domain: {}", ruby_string(domain))),
+          None => {}
+        }
+        format!(
+          "cookies.delete({}{})",
+          ruby_string(session.key()),
+          options.concat()
+        )
+      }
+    }
+  }
+}
+
+/// Generates the controller of the session flow, for an app storing its
+/// session as `session`.
+///
+/// Each step renders the session it read and the one it left, except for
+/// the deletion.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::flows;
+/// use rails_cookies_monster::profile::SessionStore;
+///
+/// let controller = flows::controller(&SessionStore::default());
+/// assert!(controller.contains("    reset_session\n"));
+/// assert!(controller.contains(r#"cookies.delete("_cookie_monster_session")"#));
+/// ```
+pub fn controller(session: &SessionStore) -> String {
+  let mut ruby = String::from("class FlowsController < MonstersController\n");
+  for (index, step) in FlowStep::ALL.iter().enumerate() {
+    if index > 0 {
+      ruby.push('\n');
+    }
+    match step {
+      FlowStep::Delete => ruby.push_str(&format!(
+        "  def {}\n    {}\n    report\n  end\n",
+        step.action(),
+        step.statements(session)
+      )),
+      _ => ruby.push_str(&format!(
+        "  def {}\n    read = session.to_hash\n    {}\n    report(session: {{ read: read, written: session.to_hash }})\n  end\n",
+        step.action(),
+        step.statements(session)
+      )),
+    }
+  }
+  ruby.push_str("end\n");
+  ruby
+}
+
+/// The routes of the session flow, drawn in the routes of the app.
+pub fn routes() -> String {
+  FlowStep::ALL
+    .iter()
+    .map(|step| format!("  get '{}' => 'flows#{}'\n", step.route(), step.action()))
+    .collect()
+}
+
+/// The cookies a client holds between the steps of a flow, by name.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::flows::CookieJar;
+///
+/// let mut jar = CookieJar::default();
+/// jar.set("_app_session=a+b; path=/; httponly");
+/// jar.set("other=1; path=/");
+/// assert_eq!(jar.header(), "_app_session=a%2Bb; other=1");
+/// jar.set("_app_session=; path=/; max-age=0; expires=Thu, 01 Jan 1970 00:00:00 GMT");
+/// assert_eq!(jar.header(), "other=1");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CookieJar(BTreeMap<String, String>);
+
+impl CookieJar {
+  /// Applies a `Set-Cookie` header, URL-decoded as captured: the cookie is
+  /// removed when emptied or expired.
+  pub fn set(&mut self, set_cookie: &str) {
+    let mut parts = set_cookie.split(';').map(str::trim);
+    let pair = parts.next().unwrap_or_default();
+    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+    let expired =
+      parts
+        .filter_map(|attribute| attribute.split_once('='))
+        .any(
+          |(attribute, value)| match attribute.to_lowercase().as_str() {
+            "max-age" => value.parse::<i64>().is_ok_and(|max_age| max_age <= 0),
+            "expires" => {
+              DateTime::parse_from_rfc2822(value).is_ok_and(|expires| expires < Utc::now())
+            }
+            _ => false,
+          },
+        );
+    if value.is_empty() || expired {
+      self.0.remove(name);
+    } else {
+      self.0.insert(name.to_string(), value.to_string());
+    }
+  }
+
+  /// The `Cookie` header sending the cookies back, their values URL-encoded
+  /// again as Rails decodes them.
+  pub fn header(&self) -> String {
+    self
+      .0
+      .iter()
+      .map(|(name, value)| format!("{}={}", name, encode(value)))
+      .collect::<Vec<_>>()
+      .join("; ")
+  }
+}
+
+/// A step of the session flow on a Rails version.
+///
+/// * step: The step requested
+/// * sent: The `Cookie` header sent, the jar left by the previous steps
+/// * session: The session Rails read from the jar (`read`) and left
+///   (`written`), missing when deleting it
+/// * cookies: The cookies the step set
+/// * jar: The cookies held after the step
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FlowStepCapture {
+  pub step: FlowStep,
+  pub sent: String,
+  pub session: Option<Value>,
+  pub cookies: Vec<CapturedCookie>,
+  pub jar: CookieJar,
+}
+
+/// The session flow on a Rails version.
+///
+/// * rails_version: The Rails version requested
+/// * profile: The configuration the Rails app was booted with
+/// * steps: The steps, in order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FlowCapture {
+  pub rails_version: String,
+  pub profile: Profile,
+  pub steps: Vec<FlowStepCapture>,
+}
@@ -1,36 +1,350 @@
 use futures::future::join_all;
 use itertools::Itertools;
-use log::{debug, error, info, trace};
-use std::collections::HashSet;
-use urlencoding::decode;
+use log::{debug, error, info, trace, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use urlencoding::{decode, encode};
 
-use reqwest::header::SET_COOKIE;
-use tokio::time::{sleep, Duration};
+use reqwest::header::{COOKIE, SET_COOKIE};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use tokio::time::Duration;
 
-use dockworker::ContainerCreateOptions;
-use dockworker::ContainerHostConfig;
-use dockworker::ExposedPorts;
-use dockworker::PortBindings;
 use semver::VersionReq;
+use serde::{Deserialize, Serialize};
 
+pub mod acceptance;
+pub mod cleanup;
+pub mod daemon;
 pub mod docker;
+pub mod flows;
+pub mod profile;
 pub mod rails;
-use docker::image_exists;
+pub mod rotation;
+pub mod runtime;
+pub mod scenarios;
+use acceptance::{AcceptanceCapture, SentCookie};
+use cleanup::{CleanupGuard, ContainerRegistry};
+use docker::DockerRuntime;
+use flows::{CookieJar, FlowCapture, FlowStep, FlowStepCapture};
+use profile::Profile;
 use rails::versions::RailsVersion;
+use rotation::{RotationCapture, RotationFlow};
+use runtime::native::NativeRuntime;
+use runtime::{ContainerRuntime, ContainerSpec, ContainerState};
+use scenarios::{Jar, Scenario};
+
+/// A running Rails container.
+///
+/// * rails_version: The Rails version served by the container
+/// * profile: The configuration the Rails app was booted with
+/// * id: The Docker container ID
+/// * address: The `host:port` the container's Rails server is reachable on,
+///   missing if it exited before its port was published
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RailsContainer {
+  pub rails_version: String,
+  pub profile: Profile,
+  pub id: String,
+  pub address: Option<String>,
+}
+
+/// The cookies set by a Rails version.
+///
+/// * rails_version: The Rails version that set the cookies
+/// * profile: The configuration the Rails app was booted with
+/// * secret_key_base: The secret the app actually used, which is generated in
+///   development and test rather than read from `SECRET_KEY_BASE`
+/// * credentials: The encrypted credentials the app read its secret from, if
+///   it was booted without `SECRET_KEY_BASE`
+/// * encrypted_file: The encrypted files exchanged with the app, with
+///   `ENCRYPTED_FILE_SUITE`
+/// * cookies: The cookies set by the app
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Capture {
+  pub rails_version: String,
+  pub profile: Profile,
+  pub secret_key_base: String,
+  pub credentials: Option<CapturedCredentials>,
+  pub encrypted_file: Option<EncryptedFileCapture>,
+  pub cookies: Vec<CapturedCookie>,
+}
+
+/// Encrypted files, as `config/credentials.yml.enc`, exchanged with a Rails
+/// app: one encrypted by this crate for Rails to read, and one encrypted by
+/// Rails for this crate to decrypt.
+///
+/// * key: The key of both files, as in `config/master.key`
+/// * contents: What both files hold
+/// * written: The file encrypted by this crate
+/// * read: What Rails read from `written`, missing if it could not
+/// * rails_written: The file encrypted by Rails
+/// * decrypted: What this crate decrypted from `rails_written`, missing if it could not
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedFileCapture {
+  pub key: String,
+  pub contents: String,
+  pub written: String,
+  pub read: Option<String>,
+  pub rails_written: String,
+  pub decrypted: Option<String>,
+}
+
+/// The encrypted credentials of a Rails app, as generated by Rails.
+///
+/// * master_key: The key of `config/master.key`
+/// * content: The content of `config/credentials.yml.enc`
+/// * secret_key_base: The secret this crate decrypted from them, missing if
+///   it could not, which cookies are decrypted with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CapturedCredentials {
+  pub master_key: String,
+  pub content: String,
+  #[serde(default)]
+  pub secret_key_base: Option<String>,
+}
+
+/// A cookie set by a Rails app.
+///
+/// * scenarios: The names of the scenarios that produced the cookie
+/// * expected: The value decoding the cookie should yield, by scenario
+/// * set_cookie: The `Set-Cookie` header, URL-decoded
+/// * expires: The `Expires` attribute of the cookie
+/// * attributes: The attributes of the `Set-Cookie` header, by lowercase name,
+///   `true` for flags such as `secure`
+/// * exp: The expiry embedded in the message of signed, encrypted and session
+///   cookies
+/// * purpose: The purpose embedded in the message of signed, encrypted and
+///   session cookies
+/// * decoded: The message of signed, encrypted and session cookies, as decoded
+///   by this crate with the serializer of the profile, the whole session hash
+///   for the session cookie
+/// * rejected: Whether Rails rejects the cookie when it is sent back to
+///   [`scenarios::READ_ROUTE`], reading nothing where its scenarios expect a
+///   value, as decoders must
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CapturedCookie {
+  pub scenarios: Vec<String>,
+  pub expected: BTreeMap<String, serde_json::Value>,
+  pub set_cookie: String,
+  pub expires: Option<String>,
+  pub attributes: BTreeMap<String, serde_json::Value>,
+  pub exp: Option<String>,
+  pub purpose: Option<String>,
+  pub decoded: Option<serde_json::Value>,
+  pub rejected: bool,
+}
+
+impl CapturedCookie {
+  fn new(
+    set_cookie: String,
+    setters: &[&Scenario],
+    container: &RailsContainer,
+    app: &AppReport,
+  ) -> Self {
+    let mut cookie = Self {
+      scenarios: setters
+        .iter()
+        .map(|scenario| scenario.name.clone())
+        .collect(),
+      expected: setters
+        .iter()
+        .map(|scenario| (scenario.name.clone(), scenario.expected()))
+        .collect(),
+      set_cookie,
+      expires: None,
+      attributes: BTreeMap::new(),
+      exp: None,
+      purpose: None,
+      decoded: None,
+      rejected: false,
+    };
+    cookie.attributes = cookie
+      .set_cookie
+      .split(';')
+      .skip(1)
+      .map(str::trim)
+      .filter(|attribute| !attribute.is_empty())
+      .map(|attribute| match attribute.split_once('=') {
+        Some((name, value)) => (name.trim().to_lowercase(), value.trim().into()),
+        None => (attribute.to_lowercase(), true.into()),
+      })
+      .collect();
+    cookie.expires = cookie
+      .attributes
+      .get("expires")
+      .and_then(serde_json::Value::as_str)
+      .map(str::to_string);
+
+    let value = cookie.pair().1;
+    let serializer = container.profile.serializer;
+    let envelope = match setters.first().map(|scenario| scenario.jar) {
+      Some(jar) if jar.is_signed() => rails::crypto::verify_cookie(
+        container.profile.key_generator(&container.rails_version),
+        &container.profile.salts,
+        app.secret(),
+        value,
+      )
+      .and_then(|data| rails::envelope::Envelope::parse(&data, serializer)),
+      // The cookie store writes the session through the encrypted jar.
+      Some(jar) if jar.is_encrypted() || jar == Jar::Session => rails::decipher_envelope(
+        serializer,
+        container.profile.cipher,
+        container.profile.key_generator(&container.rails_version),
+        &container.profile.salts,
+        app.secret(),
+        value,
+      ),
+      _ => Err("No metadata".to_string()),
+    };
+    match envelope {
+      Ok(envelope) => {
+        cookie.exp = envelope.expires_at.map(|exp| exp.to_rfc3339());
+        cookie.purpose = envelope.purpose;
+        cookie.decoded = Some(envelope.message);
+      }
+      Err(err) => trace!("No envelope read from {}: {}", cookie.pair().0, err),
+    }
+    cookie
+  }
+
+  /// The part of `decoded` that `expected[scenario]` describes: the message
+  /// itself, or the scenario's key in the session hash of the session cookie.
+  pub fn decoded_for(&self, scenario: &Scenario) -> Option<&serde_json::Value> {
+    let decoded = self.decoded.as_ref()?;
+    match scenario.jar {
+      Jar::Session => decoded.get(&scenario.name),
+      _ => Some(decoded),
+    }
+  }
+
+  /// The `name=value` pair of the cookie, without its attributes.
+  pub fn pair(&self) -> (&str, &str) {
+    let pair = self.set_cookie.split(';').next().unwrap_or_default();
+    pair.split_once('=').unwrap_or((pair, ""))
+  }
+}
+
+/// What the Rails app reports about itself in its response body.
+///
+/// The cookies it read are only reported by [`scenarios::READ_ROUTE`], the
+/// session by the steps of the session flow, and the cookie sent to
+/// [`scenarios::ACCEPT_ROUTE`] by that route.
+#[derive(Deserialize)]
+struct AppReport {
+  version: String,
+  environment: String,
+  secret_key_base: String,
+  #[serde(default)]
+  cookies: serde_json::Map<String, serde_json::Value>,
+  #[serde(default)]
+  credentials: Option<CapturedCredentials>,
+  #[serde(default)]
+  encrypted_file: Option<EncryptedFileReport>,
+  #[serde(default)]
+  session: Option<serde_json::Value>,
+  #[serde(default)]
+  accepted: serde_json::Map<String, serde_json::Value>,
+}
+
+/// What Rails read from the encrypted file it was sent, and the one it wrote.
+#[derive(Deserialize)]
+struct EncryptedFileReport {
+  read: Option<String>,
+  written: String,
+}
+
+impl AppReport {
+  /// The secret cookies are decrypted with: the one derived from the
+  /// credentials when reported, so that decryption checks it, else the one
+  /// the app reported.
+  fn secret(&self) -> &str {
+    match &self.credentials {
+      Some(credentials) => credentials.secret_key_base.as_deref().unwrap_or_default(),
+      None => &self.secret_key_base,
+    }
+  }
+}
+
+/// A Rails version that did not yield its cookies.
+///
+/// * rails_version: The Rails version that failed
+/// * profile: The configuration the Rails app was booted with
+/// * error: What went wrong
+/// * logs_tail: The last lines the container logged
+/// * logs_path: Where the full container logs were saved, if they could be
+#[derive(Clone, Debug)]
+pub struct ContainerFailure {
+  pub rails_version: String,
+  pub profile: Profile,
+  pub error: String,
+  pub logs_tail: Vec<String>,
+  pub logs_path: Option<PathBuf>,
+}
 
 /// A instance of Rails Cookies Monster tests.
 ///
+/// * run_directory: Where the files produced by this run are saved
+/// * daemon: Whether started containers are kept running after this run
+/// * isolated: Whether containers run on an internal network of their own,
+///   without outbound access, instead of publishing their ports
+/// * memory_limit: The memory cap of each container, in bytes
+/// * cpu_limit: The CPU cap of each container, in number of CPUs
+/// * profiles: The configurations each version is booted with
+/// * scenarios: The cookies the Rails apps set
+/// * each_scenario: Whether each scenario is requested on its own route,
+///   instead of all at once
+/// * rotations: The rotation flows run on each version
+/// * encrypted_files: Whether encrypted files are exchanged with each app
+/// * session_flows: Whether the session flow is run on each app
+/// * runtime: The container engine the Rails apps run on
+/// * images: The images available on the runtime, listed once per run
 /// * versions: The versions that will be checked during this run
-#[derive(Default)]
+/// * containers: The containers started for this run
+/// * cleanup: Removes the containers left behind when the run is dropped
 pub struct RailsCookiesMonster {
   pub secret: String,
   pub canary: String,
+  pub run_directory: PathBuf,
+  pub daemon: bool,
+  pub isolated: bool,
+  pub memory_limit: Option<u64>,
+  pub cpu_limit: Option<f64>,
+  pub profiles: Vec<Profile>,
+  pub scenarios: Vec<Scenario>,
+  pub each_scenario: bool,
+  pub rotations: Vec<RotationFlow>,
+  pub encrypted_files: bool,
+  pub session_flows: bool,
+  pub sent_cookies: Vec<SentCookie>,
+  pub encoded_cookies: bool,
+  runtime: Arc<dyn ContainerRuntime>,
+  images: OnceCell<HashSet<String>>,
   versions: HashSet<RailsVersion>,
-  containers: HashSet<(String, String)>,
+  containers: HashSet<RailsContainer>,
+  cleanup: CleanupGuard,
+}
+
+impl Default for RailsCookiesMonster {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl RailsCookiesMonster {
+  /// Creates an instance running its Rails apps on the runtime named by
+  /// `RAILS_RUNNER`: `docker` (the default) or `native`.
   pub fn new() -> Self {
+    let runtime: Arc<dyn ContainerRuntime> = match std::env::var("RAILS_RUNNER").as_deref() {
+      Ok("native") => Arc::new(NativeRuntime::default()),
+      _ => Arc::new(DockerRuntime),
+    };
+    Self::with_runtime(runtime)
+  }
+
+  /// Creates an instance running its Rails apps on `runtime`.
+  pub fn with_runtime(runtime: Arc<dyn ContainerRuntime>) -> Self {
     let secret = match std::env::var("SECRET_KEY_BASE") {
       Ok(value) => value,
       Err(_) => {
@@ -46,15 +360,151 @@ impl RailsCookiesMonster {
         "correct-horse-battery-staple".to_string()
       }
     };
+    let run_directory = match std::env::var("RUN_DIRECTORY") {
+      Ok(value) => PathBuf::from(value),
+      Err(_) => {
+        let timestamp = SystemTime::now()
+          .duration_since(UNIX_EPOCH)
+          .unwrap()
+          .as_secs();
+        PathBuf::from("runs").join(timestamp.to_string())
+      }
+    };
+    let isolated = std::env::var("ISOLATED_NETWORK").is_ok();
+    let memory_limit = std::env::var("CONTAINER_MEMORY")
+      .ok()
+      .and_then(|value| parse_memory(&value));
+    let cpu_limit = std::env::var("CONTAINER_CPUS")
+      .ok()
+      .and_then(|value| value.parse().ok());
     debug!("Initialization:");
     debug!("- Using SECRET_KEY_BASE: {}", secret);
     debug!("- Using CANARY_VALUE: {}", canary);
+    debug!("- Using RUN_DIRECTORY: {}", run_directory.display());
+    debug!("- Using isolated network: {}", isolated);
+    debug!("- Using CONTAINER_MEMORY: {:?}", memory_limit);
+    debug!("- Using CONTAINER_CPUS: {:?}", cpu_limit);
+    let mut scenarios = match std::env::var("COOKIE_SCENARIOS") {
+      Ok(path) => scenarios::load(&path)
+        .unwrap_or_else(|err| panic!("Could not load COOKIE_SCENARIOS: {}", err)),
+      Err(_) => scenarios::default_scenarios(&canary),
+    };
+    if std::env::var("EXPIRY_SUITE").is_ok() {
+      scenarios.extend(scenarios::expiry_suite(&canary));
+    }
+    if std::env::var("PURPOSE_SUITE").is_ok() {
+      scenarios.extend(scenarios::purpose_suite(&canary));
+    }
+    if let Ok(jars) = std::env::var("PAYLOAD_SUITE") {
+      for jar in jars.split(',').map(str::trim) {
+        match jar.parse().and_then(scenarios::payload_suite) {
+          Ok(suite) => scenarios.extend(suite),
+          Err(err) => warn!("Ignoring PAYLOAD_SUITE entry: {}", err),
+        }
+      }
+    }
+    let each_scenario = std::env::var("SCENARIO_REQUESTS").as_deref() == Ok("each");
+    debug!(
+      "- Using scenarios: {}",
+      scenarios.iter().map(|scenario| &scenario.name).join(", ")
+    );
+    let sent_cookies = match std::env::var("ACCEPT_COOKIES") {
+      Ok(path) => acceptance::load(&path)
+        .unwrap_or_else(|err| panic!("Could not load ACCEPT_COOKIES: {}", err)),
+      Err(_) => vec![],
+    };
+    let profiles = profile::profiles();
+    debug!(
+      "- Using profiles: {}",
+      profiles.iter().map(Profile::label).join(", ")
+    );
+    let mut rotations = match std::env::var("ROTATION_SUITE") {
+      Ok(kinds) => {
+        rotation::rotation_suite(&kinds.split(',').map(str::trim).collect_vec(), &secret)
+      }
+      Err(_) => vec![],
+    };
+    let (salts, session) = (profile::salts(), profile::session_store());
+    for flow in &mut rotations {
+      for profile in [
+        &mut flow.from,
+        &mut flow.to,
+      ] {
+        profile.salts = salts.clone();
+        profile.session = session.clone();
+      }
+    }
+    debug!(
+      "- Using rotations: {}",
+      rotations.iter().map(|flow| &flow.name).join(", ")
+    );
 
     Self {
       secret,
       canary,
+      run_directory,
+      daemon: false,
+      isolated,
+      memory_limit,
+      cpu_limit,
+      profiles,
+      scenarios,
+      each_scenario,
+      rotations,
+      encrypted_files: std::env::var("ENCRYPTED_FILE_SUITE").is_ok(),
+      session_flows: std::env::var("SESSION_FLOW_SUITE").is_ok(),
+      sent_cookies,
+      encoded_cookies: std::env::var("ENCODER_SUITE").is_ok(),
+      runtime: runtime.clone(),
+      images: OnceCell::new(),
       versions: HashSet::new(),
       containers: HashSet::new(),
+      cleanup: CleanupGuard::new(runtime),
+    }
+  }
+
+  /// Removes the containers of this run if the process is interrupted.
+  pub fn cleanup_on_signal(&self) {
+    cleanup::spawn_signal_handler(self.cleanup.registry.clone(), self.runtime.clone());
+  }
+
+  /// Removes containers and networks left behind by previous runs, those
+  /// whose process is gone: concurrent runs keep theirs.
+  pub async fn sweep_containers(&self) {
+    // Labelled before runs recorded their process, so necessarily gone.
+    let gone = |owner: &str| match owner.parse() {
+      Ok(pid) => pid != std::process::id() && !cleanup::process_alive(pid),
+      Err(_) => true,
+    };
+    match self.runtime.labelled_containers(runtime::RUN_LABEL).await {
+      Ok(labelled) => {
+        let leftovers: Vec<String> = labelled
+          .into_iter()
+          .filter(|(_, owner)| gone(owner))
+          .map(|(id, _)| id)
+          .collect();
+        if leftovers.is_empty() {
+          trace!("No leftover containers from previous runs");
+        } else {
+          warn!(
+            "Removing {} leftover containers from previous runs",
+            leftovers.len()
+          );
+          RailsCookiesMonster::drop_containers(self.runtime.clone(), leftovers).await;
+        }
+      }
+      Err(err) => error!("Failed to list leftover containers: {}", err),
+    }
+    // Interrupted runs leave their network behind too, once it is empty.
+    let networks = match self.runtime.labelled_networks(runtime::RUN_LABEL).await {
+      Ok(networks) => networks,
+      Err(err) => return error!("Failed to list leftover networks: {}", err),
+    };
+    for (network, _) in networks.into_iter().filter(|(_, owner)| gone(owner)) {
+      match self.runtime.remove_network(&network).await {
+        Ok(_) => trace!("Removed network {}", network),
+        Err(err) => debug!("Kept network {}: {}", network, err),
+      }
     }
   }
 
@@ -80,7 +530,6 @@ impl RailsCookiesMonster {
     let mut ruby_versions: Vec<String> = self
       .versions
       .iter()
-      .cloned()
       .map(|version| version.ruby.to_string())
       .collect();
     ruby_versions.sort();
@@ -98,17 +547,18 @@ impl RailsCookiesMonster {
   ///
   /// # Examples
   /// ```
+  /// use rails_cookies_monster::RailsCookiesMonster;
+  ///
   /// let mut monster = RailsCookiesMonster::new();
   /// monster.add_version_requirement(">=7.0");
   /// let versions = monster.rails_versions();
-  /// // Returns something like ["7.0.0", "7.0.1", "7.0.2", ...]
-  /// assert_eq!(versions[0], "7.0.0");
+  /// // Returns something like [("3.0.7", "7.0.0", "7.0.x"), ("3.3.7", "7.0.1", "7.0.x"), ...]
+  /// assert_eq!(versions[0].1, "7.0.0");
   /// ```
   pub fn rails_versions(&self) -> Vec<(String, String, String)> {
     let mut rails_versions: Vec<(String, String, String)> = self
       .versions
       .iter()
-      .cloned()
       .map(|version| {
         (
           version.ruby.to_owned(),
@@ -121,29 +571,43 @@ impl RailsCookiesMonster {
     rails_versions
   }
 
-  async fn cache_available_images(&self) {
-    if docker::IMAGES.get().is_none() {
-      debug!("Caching list of available Docker images");
-      docker::cache_images().await;
-      debug!(
-        "-> Cached list of {} Docker images",
-        docker::IMAGES.get().unwrap().len()
-      );
+  async fn cache_available_images(&self) -> &HashSet<String> {
+    self
+      .images
+      .get_or_init(|| async {
+        debug!("Caching list of available Docker images");
+        let images: HashSet<String> = match self.runtime.list_images().await {
+          Ok(images) => images.into_iter().collect(),
+          Err(err) => {
+            error!("Error: Failed to list available Docker images: {}", err);
+            HashSet::new()
+          }
+        };
+        debug!("-> Cached list of {} Docker images", images.len());
+        images
+      })
+      .await
+  }
+
+  async fn image_exists(&self, image_tag: &str) -> bool {
+    let image_full_tag = if image_tag.starts_with("rails-cookies-everywhere:") {
+      image_tag.to_string()
     } else {
-      trace!("Docker images list already cached");
-    }
+      format!("rails-cookies-everywhere:{}", image_tag)
+    };
+    self
+      .cache_available_images()
+      .await
+      .contains(&image_full_tag)
   }
 
   pub async fn build_base_image(&self) -> Result<(), Vec<(String, String)>> {
-    self.cache_available_images().await;
-
-    let missing_bases: Vec<String> = self
-      .ruby_versions()
-      .iter()
-      .unique()
-      .filter(|version| !image_exists(&format!("ruby-base-{}", version)))
-      .cloned()
-      .collect();
+    let mut missing_bases: Vec<String> = vec![];
+    for version in self.ruby_versions().into_iter().unique() {
+      if !self.image_exists(&format!("ruby-base-{}", version)).await {
+        missing_bases.push(version);
+      }
+    }
     if missing_bases.is_empty() {
       trace!("All Ruby base images are already built!");
       return Ok(());
@@ -151,9 +615,12 @@ impl RailsCookiesMonster {
 
     info!("Building {} Ruby version images", missing_bases.len());
     let tasks = missing_bases.iter().cloned().map(|missing_base| {
+      let runtime = self.runtime.clone();
       tokio::spawn(async move {
         info!("Building ruby-{} image", missing_base);
-        let task = docker::build::base(&missing_base).await;
+        let task = runtime
+          .build_image(&docker::build::base(&missing_base))
+          .await;
         match &task {
           Ok(_) => Ok(()),
           Err(error) => Err((missing_base, error.clone())),
@@ -178,19 +645,14 @@ impl RailsCookiesMonster {
   }
 
   pub async fn build_versions_images(&self) -> Result<(), Vec<(String, String)>> {
-    self.cache_available_images().await;
-
-    let missing_versions: Vec<(String, String, String)> = self
-      .rails_versions()
-      .iter()
-      .filter(|(_, rails_version, _)| {
-        if std::env::var("CACHE_DOCKER_IMAGES").is_err() {
-          return true;
-        }
-        !image_exists(&format!("rails-v{}", rails_version))
-      })
-      .cloned()
-      .collect();
+    let mut missing_versions: Vec<(String, String, String)> = vec![];
+    for version in self.rails_versions() {
+      if std::env::var("CACHE_DOCKER_IMAGES").is_err()
+        || !self.image_exists(&format!("rails-v{}", version.1)).await
+      {
+        missing_versions.push(version);
+      }
+    }
     if missing_versions.is_empty() {
       trace!("All Rails version images are already built");
       return Ok(());
@@ -201,9 +663,11 @@ impl RailsCookiesMonster {
       .iter()
       .cloned()
       .map(|(ruby_version, rails_version, patch)| {
+        let runtime = self.runtime.clone();
         tokio::spawn(async move {
           info!("Building Rails v{} image", rails_version);
-          let task = docker::build::version(&ruby_version, &rails_version, &patch).await;
+          let image = docker::build::version(&ruby_version, &rails_version, &patch);
+          let task = runtime.build_image(&image).await;
           match &task {
             Ok(_) => Ok(()),
             Err(error) => Err((rails_version, error.clone())),
@@ -228,6 +692,13 @@ impl RailsCookiesMonster {
   }
 
   pub async fn start_containers(&mut self) {
+    if let Some(network) = self.network() {
+      let labels = HashMap::from([self.owner_label()]);
+      if let Err(err) = self.runtime.create_network(&network, &labels).await {
+        return error!("Failed to create network {}: {}", network, err);
+      }
+      debug!("Using network {}", network);
+    }
     let mut versions_list: Vec<_> = self
       .rails_versions()
       .iter()
@@ -237,158 +708,1101 @@ impl RailsCookiesMonster {
     versions_list.sort();
     let ids = versions_list
       .iter()
-      .cloned()
-      .enumerate()
-      .map(|(i, rails_version)| {
+      .cartesian_product(self.all_profiles())
+      .filter(|(rails_version, profile)| {
+        let supported = profile.supports(rails_version);
+        if !supported {
+          info!(
+            "Skipping Rails {} ({}): not supported by this version",
+            rails_version,
+            profile.label()
+          );
+        }
+        supported
+      })
+      .map(|(rails_version, profile)| {
+        let runtime = self.runtime.clone();
+        let rails_version = rails_version.clone();
+        let spec = self.container_spec(&rails_version, &profile);
+        // Daemon containers must outlive the run, so they are not cleaned up.
+        let registry = (!self.daemon).then(|| self.cleanup.registry.clone());
         tokio::spawn(async move {
-          let image_tag = format!("rails-cookies-everywhere:rails-v{}", rails_version);
-          let mut host_config = ContainerHostConfig::new();
-          host_config.port_bindings(PortBindings(vec![(
-            3000,
-            "tcp".to_string(),
-            3000 + i as u16,
-          )]));
-          let mut options = ContainerCreateOptions::new(&image_tag);
-          options
-            .env(format!(
-              "SECRET_KEY_BASE={}",
-              std::env::var("SECRET_KEY_BASE").unwrap()
-            ))
-            .env(format!(
-              "CANARY_VALUE={}",
-              std::env::var("CANARY_VALUE").unwrap()
-            ))
-            .exposed_ports(ExposedPorts(vec![(3000, "tcp".to_string())]))
-            .host_config(host_config);
-
-          let container_tag = format!("rails-cookies-everywhere-rails-v{}", rails_version);
-          let container = docker::DOCKER
-            .lock()
-            .await
-            .create_container(Some(&container_tag), &options)
-            .await
-            .unwrap();
-          docker::DOCKER
-            .lock()
-            .await
-            .start_container(&container.id)
-            .await
-            .unwrap();
-          (rails_version, container.id)
+          Self::start_container(runtime.as_ref(), &rails_version, &profile, &spec, registry).await
         })
       });
 
     let results = join_all(ids).await;
     debug!("Started {} containers", results.len());
-    results
-      .iter()
-      .filter_map(|r| r.as_ref().ok())
-      .for_each(|(rails_version, container_id)| {
-        debug!("- Container for {}: {}", &rails_version, &container_id);
-        self
-          .containers
-          .insert((rails_version.to_owned(), container_id.to_owned()));
-      });
+    for result in results.into_iter().filter_map(|r| r.ok()) {
+      match result {
+        Ok(container) => {
+          debug!(
+            "- Container for {} ({}): {} on {:?}",
+            &container.rails_version,
+            container.profile.label(),
+            &container.id,
+            &container.address
+          );
+          self.containers.insert(container);
+        }
+        Err((container, err)) => error!("Failed to start container {}: {}", container, err),
+      }
+    }
   }
 
-  pub async fn query_containers(&self) -> Vec<(String, String)> {
-    let mut rails_versions: Vec<_> = self
-      .containers
+  fn container_spec(&self, rails_version: &str, profile: &Profile) -> ContainerSpec {
+    // Concurrent runs name and label their containers after their process.
+    let (label, owner) = self.owner_label();
+    let name = match self.daemon {
+      true => "rails-cookies-monster-daemon".to_string(),
+      false => format!("rails-cookies-everywhere-{}", owner),
+    };
+    // A variable without a value is unset, so that Rails reads the credentials.
+    let secret = match profile.credentials {
+      true => "SECRET_KEY_BASE".to_string(),
+      false => format!(
+        "SECRET_KEY_BASE={}",
+        profile.secret.as_ref().unwrap_or(&self.secret)
+      ),
+    };
+    let mut env = vec![
+      secret,
+      format!("CANARY_VALUE={}", self.canary),
+    ];
+    env.extend(profile.env());
+    ContainerSpec {
+      name: format!("{}-rails-v{}-{}", name, rails_version, profile.label()),
+      image: format!("rails-cookies-everywhere:rails-v{}", rails_version),
+      env,
+      labels: HashMap::from([(label, owner)]),
+      network: self.network(),
+      memory: self.memory_limit,
+      cpus: self.cpu_limit,
+      files: [
+        (profile::INITIALIZER_PATH.to_string(), profile.initializer()),
+        (
+          flows::CONTROLLER_PATH.to_string(),
+          flows::controller(&profile.session),
+        ),
+      ]
+      .into_iter()
+      .chain(scenarios::files(&self.scenarios))
+      .collect(),
+    }
+  }
+
+  /// The profiles each version is booted with, those of the rotation flows included.
+  fn all_profiles(&self) -> Vec<Profile> {
+    let flows = self.rotations.iter().flat_map(|flow| {
+      [
+        flow.from.clone(),
+        flow.to.clone(),
+      ]
+    });
+    self
+      .profiles
       .iter()
-      .map(|(rails_version, _)| rails_version)
       .cloned()
+      .chain(flows)
+      .unique()
+      .collect()
+  }
+
+  /// The label marking the containers and network of this run as its own,
+  /// with the ID of its process, see [`RUN_LABEL`](runtime::RUN_LABEL).
+  fn owner_label(&self) -> (String, String) {
+    match self.daemon {
+      true => (daemon::DAEMON_LABEL.to_string(), "true".to_string()),
+      false => (
+        runtime::RUN_LABEL.to_string(),
+        std::process::id().to_string(),
+      ),
+    }
+  }
+
+  /// The internal network the containers run on, if isolated.
+  ///
+  /// Each run has its own, named after its process, so that concurrent runs
+  /// do not remove it from under each other. There is one daemon at a time.
+  fn network(&self) -> Option<String> {
+    match (self.isolated, self.daemon) {
+      (false, _) => None,
+      (true, false) => Some(run_network(&std::process::id().to_string())),
+      (true, true) => Some("rails-cookies-monster-daemon".to_string()),
+    }
+  }
+
+  async fn start_container(
+    runtime: &dyn ContainerRuntime,
+    rails_version: &str,
+    profile: &Profile,
+    spec: &ContainerSpec,
+    registry: Option<ContainerRegistry>,
+  ) -> Result<RailsContainer, (String, String)> {
+    let with_version = |err: String| (spec.name.clone(), err);
+    let container_id = runtime.create_container(spec).await.map_err(with_version)?;
+    if let Some(registry) = registry {
+      registry.track(&container_id);
+    }
+    runtime
+      .start_container(&container_id)
+      .await
+      .map_err(with_version)?;
+    let state = runtime
+      .inspect_container(&container_id)
+      .await
+      .map_err(with_version)?;
+    Ok(RailsContainer {
+      rails_version: rails_version.to_owned(),
+      profile: profile.clone(),
+      id: container_id,
+      address: state.address,
+    })
+  }
+
+  /// Persists the containers of this run for later invocations.
+  pub fn save_daemon_state(&self) -> Result<(), String> {
+    let mut containers: Vec<_> = self.containers.iter().cloned().collect();
+    containers
+      .sort_by_key(|container| (container.rails_version.clone(), container.profile.label()));
+    daemon::save(&daemon::DaemonState {
+      secret: self.secret.clone(),
+      canary: self.canary.clone(),
+      scenarios: self.scenarios.clone(),
+      rotations: self.rotations.clone(),
+      containers,
+    })
+  }
+
+  /// Takes over the containers kept running by a previous `serve`.
+  ///
+  /// If version requirements were added, only their containers are kept.
+  pub fn load_daemon_state(&mut self) -> Result<(), String> {
+    let state = daemon::load()?;
+    let requested: HashSet<String> = self
+      .rails_versions()
+      .into_iter()
+      .map(|(_, rails_version, _)| rails_version)
       .collect();
-    rails_versions.sort();
-    let cookies = rails_versions
+    self.daemon = true;
+    self.secret = state.secret;
+    self.canary = state.canary;
+    self.scenarios = state.scenarios;
+    self.rotations = state.rotations;
+    self.containers = state
+      .containers
+      .into_iter()
+      .filter(|container| requested.is_empty() || requested.contains(&container.rails_version))
+      .collect();
+    Ok(())
+  }
+
+  /// Returns the containers of this run, sorted by version, with their state.
+  pub async fn list_containers(&self) -> Vec<(RailsContainer, Result<ContainerState, String>)> {
+    let mut containers: Vec<_> = self.containers.iter().cloned().collect();
+    containers
+      .sort_by_key(|container| (container.rails_version.clone(), container.profile.label()));
+    let mut listed = vec![];
+    for container in containers {
+      let state = self.runtime.inspect_container(&container.id).await;
+      listed.push((container, state));
+    }
+    listed
+  }
+
+  /// Replaces the containers of a Rails version, one per profile, with fresh ones.
+  pub async fn restart_container(&mut self, rails_version: &str) -> Result<(), String> {
+    let containers: Vec<_> = self
+      .containers
       .iter()
+      .filter(|container| container.rails_version == rails_version)
       .cloned()
-      .enumerate()
-      .map(|(i, rails_version)| {
-        let port = 3000 + i as u16;
-        tokio::spawn(async move {
-          let url = format!("http://localhost:{}/", port);
-          let mut count = 0;
-          loop {
-            sleep(Duration::from_millis(1000)).await;
-            match reqwest::get(&url).await {
-              Ok(response) => {
-                let headers = response
-                  .headers()
-                  .get_all(SET_COOKIE)
-                  .iter()
-                  .map(|cookie| {
-                    (
-                      rails_version.clone(),
-                      decode(cookie.to_str().unwrap()).unwrap().to_string(),
-                    )
-                  })
-                  .collect();
-                let body = response.text().await.unwrap();
-                assert_eq!(
-                  body,
-                  format!(r#"{{"version":"{}"}}"#, rails_version),
-                  "Wrong versin body: {}",
-                  body
-                );
-
-                break headers;
-              }
-              Err(_err) => {
-                if count > 10 {
-                  error!(
-                    "Failed to query container {} after {} attempts",
-                    rails_version, count
-                  );
-                  break vec![];
-                }
-                count += 1;
-              }
+      .collect();
+    if containers.is_empty() {
+      return Err(format!("No container for Rails v{}", rails_version));
+    }
+
+    for container in containers {
+      self.runtime.remove_container(&container.id).await?;
+      self.containers.remove(&container);
+
+      let spec = self.container_spec(rails_version, &container.profile);
+      let registry = (!self.daemon).then(|| self.cleanup.registry.clone());
+      let restarted = Self::start_container(
+        self.runtime.as_ref(),
+        rails_version,
+        &container.profile,
+        &spec,
+        registry,
+      )
+      .await
+      .map_err(|(_, err)| err)?;
+      info!(
+        "Restarted Rails v{} ({}) as {} on {:?}",
+        rails_version,
+        restarted.profile.label(),
+        restarted.id,
+        restarted.address
+      );
+      self.containers.insert(restarted);
+    }
+    Ok(())
+  }
+
+  /// How long a container may take to boot its Rails server.
+  ///
+  /// Read from `CONTAINER_BOOT_TIMEOUT` (in seconds), defaults to 120 seconds.
+  fn boot_timeout() -> Duration {
+    let seconds = std::env::var("CONTAINER_BOOT_TIMEOUT")
+      .ok()
+      .and_then(|value| value.parse().ok())
+      .unwrap_or(120);
+    Duration::from_secs(seconds)
+  }
+
+  /// Queries every container for its cookies.
+  ///
+  /// Returns the cookies of the versions that answered, and a failure for each
+  /// version that did not. The logs of failed containers are saved to the run
+  /// directory, as are the logs of all containers if `SAVE_CONTAINER_LOGS` is set.
+  pub async fn query_containers(&self) -> (Vec<Capture>, Vec<ContainerFailure>) {
+    let mut containers: Vec<_> = self.containers.iter().cloned().collect();
+    containers
+      .sort_by_key(|container| (container.rails_version.clone(), container.profile.label()));
+    let timeout = Self::boot_timeout();
+    let save_logs = std::env::var("SAVE_CONTAINER_LOGS").is_ok();
+    let scenarios = Arc::new(self.scenarios.clone());
+    let each_scenario = self.each_scenario;
+    let encrypted_file = self
+      .encrypted_files
+      .then(|| format!("canary: {}\n", self.canary));
+    let cookies = containers.into_iter().map(|container| {
+      let run_directory = self.run_directory.clone();
+      let runtime = self.runtime.clone();
+      let scenarios = scenarios.clone();
+      let encrypted_file = encrypted_file.clone();
+      tokio::spawn(async move {
+        let result = Self::query_container(
+          runtime.as_ref(),
+          &container,
+          &scenarios,
+          each_scenario,
+          encrypted_file.as_deref(),
+          timeout,
+        )
+        .await;
+        match result {
+          Ok(capture) => {
+            if save_logs {
+              Self::fetch_logs(runtime.as_ref(), &run_directory, &container).await;
             }
+            Ok(capture)
           }
-        })
-      });
+          Err(error) => {
+            error!(
+              "Failed to query container {} ({}): {}",
+              container.rails_version,
+              container.profile.label(),
+              error
+            );
+            Err(Self::failure(runtime.as_ref(), &run_directory, &container, error).await)
+          }
+        }
+      })
+    });
 
     let responses = join_all(cookies).await;
 
-    let cookies: Vec<_> = responses
+    let mut captures = vec![];
+    let mut failures = vec![];
+    for response in responses {
+      match response.unwrap() {
+        Ok(capture) => captures.push(capture),
+        Err(failure) => failures.push(failure),
+      }
+    }
+    (captures, failures)
+  }
+
+  async fn query_container(
+    runtime: &dyn ContainerRuntime,
+    container: &RailsContainer,
+    scenarios: &[Scenario],
+    each_scenario: bool,
+    encrypted_file: Option<&str>,
+    timeout: Duration,
+  ) -> Result<Capture, String> {
+    runtime::wait_until_ready(runtime, &container.id, timeout).await?;
+
+    let Some(address) = &container.address else {
+      return Err("Container has no published port".to_string());
+    };
+
+    let mut cookies = vec![];
+    let app = if each_scenario {
+      let mut app = None;
+      for scenario in scenarios {
+        let (set_cookies, report) =
+          Self::request_app(container, address, &scenarios::route(scenario), &[]).await?;
+        let mut set = set_cookies
+          .into_iter()
+          .map(|set_cookie| CapturedCookie::new(set_cookie, &[scenario], container, &report))
+          .collect_vec();
+        Self::read_back(container, address, &mut set).await?;
+        cookies.extend(set);
+        app = Some(report);
+      }
+      match app {
+        Some(app) => app,
+        None => Self::request_app(container, address, "/", &[]).await?.1,
+      }
+    } else {
+      let (set_cookies, app) = Self::request_app(container, address, "/", &[]).await?;
+      cookies.extend(Self::capture_all(set_cookies, scenarios, container, &app));
+      Self::read_back(container, address, &mut cookies).await?;
+      app
+    };
+
+    let encrypted_file = match encrypted_file {
+      Some(contents) => Some(Self::exchange_encrypted_file(container, address, contents).await?),
+      None => None,
+    };
+
+    Ok(Capture {
+      rails_version: container.rails_version.clone(),
+      profile: container.profile.clone(),
+      secret_key_base: app.secret_key_base,
+      credentials: app.credentials,
+      encrypted_file,
+      cookies,
+    })
+  }
+
+  /// Sends `cookies` back to the [`scenarios::READ_ROUTE`] of the app, and
+  /// records those it rejects: it reads nothing from them where their
+  /// scenarios expect a value.
+  async fn read_back(
+    container: &RailsContainer,
+    address: &str,
+    cookies: &mut [CapturedCookie],
+  ) -> Result<(), String> {
+    if cookies.is_empty() {
+      return Ok(());
+    }
+    let header = cookie_header(cookies);
+    let (_, app) = Self::request_app(
+      container,
+      address,
+      scenarios::READ_ROUTE,
+      &[(COOKIE.as_str(), &header)],
+    )
+    .await?;
+    for cookie in cookies {
+      cookie.rejected = cookie.expected.iter().any(|(scenario, expected)| {
+        !expected.is_null()
+          && app
+            .cookies
+            .get(scenario)
+            .is_none_or(serde_json::Value::is_null)
+      });
+    }
+    Ok(())
+  }
+
+  /// Sends Rails a file holding `contents` encrypted with a new key, and
+  /// decrypts the one it encrypts with that key in return.
+  async fn exchange_encrypted_file(
+    container: &RailsContainer,
+    address: &str,
+    contents: &str,
+  ) -> Result<EncryptedFileCapture, String> {
+    let key = rails::credentials::generate_key();
+    let written = rails::credentials::encrypt(&key, contents)?;
+    let headers = [
+      (scenarios::ENCRYPTED_FILE_KEY_HEADER, key.as_str()),
+      (scenarios::ENCRYPTED_FILE_HEADER, written.as_str()),
+    ];
+    let (_, app) = Self::request_app(
+      container,
+      address,
+      scenarios::ENCRYPTED_FILE_ROUTE,
+      &headers,
+    )
+    .await?;
+    let Some(report) = app.encrypted_file else {
+      return Err("No encrypted file in the response".to_string());
+    };
+    let decrypted = rails::credentials::decrypt(&key, &report.written)
+      .inspect_err(|err| {
+        warn!(
+          "Could not decrypt the encrypted file of Rails v{}: {}",
+          container.rails_version, err
+        )
+      })
+      .ok();
+    Ok(EncryptedFileCapture {
+      key,
+      contents: contents.to_string(),
+      written,
+      read: report.read,
+      rails_written: report.written,
+      decrypted,
+    })
+  }
+
+  /// Captures the cookies set by every scenario at once: the cookie name tells
+  /// which scenarios set it.
+  fn capture_all(
+    set_cookies: Vec<String>,
+    scenarios: &[Scenario],
+    container: &RailsContainer,
+    app: &AppReport,
+  ) -> Vec<CapturedCookie> {
+    set_cookies
+      .into_iter()
+      .map(|set_cookie| {
+        let name = set_cookie.split(['=', ';']).next().unwrap_or_default();
+        let session_key = container.profile.session.key();
+        let setters: Vec<_> = scenarios
+          .iter()
+          .filter(|scenario| scenario.cookie_name(session_key) == name)
+          .collect();
+        CapturedCookie::new(set_cookie.clone(), &setters, container, app)
+      })
+      .collect()
+  }
+
+  /// Runs the rotation flows on every version with containers for both ends.
+  ///
+  /// The cookies of every scenario are set by the `from` app, and sent to the
+  /// [`scenarios::READ_ROUTE`] of the `to` app, which rewrites those it rotates.
+  pub async fn query_rotations(&self) -> (Vec<RotationCapture>, Vec<ContainerFailure>) {
+    let timeout = Self::boot_timeout();
+    let mut flows = vec![];
+    for flow in &self.rotations {
+      let versions = self
+        .containers
+        .iter()
+        .map(|container| &container.rails_version)
+        .unique()
+        .sorted();
+      for rails_version in versions {
+        let find = |profile: &Profile| {
+          self.containers.iter().find(|container| {
+            &container.rails_version == rails_version && &container.profile == profile
+          })
+        };
+        if let (Some(from), Some(to)) = (find(&flow.from), find(&flow.to)) {
+          flows.push((flow, from, to));
+        }
+      }
+    }
+
+    let results = join_all(flows.into_iter().map(|(flow, from, to)| async move {
+      let result = Self::query_rotation(
+        self.runtime.as_ref(),
+        flow,
+        from,
+        to,
+        &self.scenarios,
+        timeout,
+      )
+      .await;
+      match result {
+        Ok(capture) => Ok(capture),
+        Err(error) => {
+          error!(
+            "Failed to rotate {} cookies on Rails {}: {}",
+            flow.name, to.rails_version, error
+          );
+          Err(Self::failure(self.runtime.as_ref(), &self.run_directory, to, error).await)
+        }
+      }
+    }))
+    .await;
+    results.into_iter().partition_result()
+  }
+
+  /// Runs the session flow on every container, if enabled.
+  ///
+  /// Each step is sent the cookies set by the previous ones, as a browser would.
+  pub async fn query_flows(&self) -> (Vec<FlowCapture>, Vec<ContainerFailure>) {
+    if !self.session_flows {
+      return (vec![], vec![]);
+    }
+    let timeout = Self::boot_timeout();
+    let containers = self
+      .containers
       .iter()
-      .flat_map(|r| r.as_ref().unwrap())
-      .cloned()
+      .sorted_by_key(|container| (container.rails_version.clone(), container.profile.label()));
+    let results = join_all(containers.map(|container| async move {
+      match Self::query_flow(self.runtime.as_ref(), container, timeout).await {
+        Ok(capture) => Ok(capture),
+        Err(error) => {
+          error!(
+            "Failed to run the session flow on Rails {} ({}): {}",
+            container.rails_version,
+            container.profile.label(),
+            error
+          );
+          Err(Self::failure(self.runtime.as_ref(), &self.run_directory, container, error).await)
+        }
+      }
+    }))
+    .await;
+    results.into_iter().partition_result()
+  }
+
+  async fn query_flow(
+    runtime: &dyn ContainerRuntime,
+    container: &RailsContainer,
+    timeout: Duration,
+  ) -> Result<FlowCapture, String> {
+    runtime::wait_until_ready(runtime, &container.id, timeout).await?;
+    let Some(address) = &container.address else {
+      return Err("Container has no published port".to_string());
+    };
+
+    let mut jar = CookieJar::default();
+    let mut steps = vec![];
+    for step in FlowStep::ALL {
+      let sent = jar.header();
+      let headers = match sent.is_empty() {
+        true => vec![],
+        false => vec![(COOKIE.as_str(), sent.as_str())],
+      };
+      let (set_cookies, app) =
+        Self::request_app(container, address, &step.route(), &headers).await?;
+      set_cookies
+        .iter()
+        .for_each(|set_cookie| jar.set(set_cookie));
+      let cookies = set_cookies
+        .into_iter()
+        .map(|set_cookie| CapturedCookie::new(set_cookie, &[], container, &app))
+        .collect();
+      steps.push(FlowStepCapture {
+        step,
+        sent,
+        session: app.session,
+        cookies,
+        jar: jar.clone(),
+      });
+    }
+    Ok(FlowCapture {
+      rails_version: container.rails_version.clone(),
+      profile: container.profile.clone(),
+      steps,
+    })
+  }
+
+  /// Sends every cookie of `ACCEPT_COOKIES`, and those of the encoder suite
+  /// if enabled, to every container, and checks that Rails reads them through
+  /// their jar.
+  pub async fn query_acceptance(&self) -> (Vec<AcceptanceCapture>, Vec<ContainerFailure>) {
+    if self.sent_cookies.is_empty() && !self.encoded_cookies {
+      return (vec![], vec![]);
+    }
+    let timeout = Self::boot_timeout();
+    let canary = self.encoded_cookies.then_some(self.canary.as_str());
+    let containers = self
+      .containers
+      .iter()
+      .sorted_by_key(|container| (container.rails_version.clone(), container.profile.label()));
+    let results = join_all(containers.map(|container| async move {
+      let result = Self::query_accept(
+        self.runtime.as_ref(),
+        container,
+        &self.sent_cookies,
+        canary,
+        timeout,
+      )
+      .await;
+      match result {
+        Ok(captures) => Ok(captures),
+        Err(error) => {
+          error!(
+            "Failed to send cookies to Rails {} ({}): {}",
+            container.rails_version,
+            container.profile.label(),
+            error
+          );
+          Err(Self::failure(self.runtime.as_ref(), &self.run_directory, container, error).await)
+        }
+      }
+    }))
+    .await;
+    let (captures, failures): (Vec<Vec<_>>, _) = results.into_iter().partition_result();
+    (captures.concat(), failures)
+  }
+
+  /// Sends `cookies` to the container one by one, and the cookies of the
+  /// encoder suite holding `canary` if set.
+  async fn query_accept(
+    runtime: &dyn ContainerRuntime,
+    container: &RailsContainer,
+    cookies: &[SentCookie],
+    canary: Option<&str>,
+    timeout: Duration,
+  ) -> Result<Vec<AcceptanceCapture>, String> {
+    runtime::wait_until_ready(runtime, &container.id, timeout).await?;
+    let Some(address) = &container.address else {
+      return Err("Container has no published port".to_string());
+    };
+
+    let mut cookies = cookies.to_vec();
+    if let Some(canary) = canary {
+      // Development and test apps generate their secret, ask for it first.
+      let (_, app) = Self::request_app(container, address, scenarios::READ_ROUTE, &[]).await?;
+      let profile = &container.profile;
+      let encoder = profile.encoder(&container.rails_version, app.secret());
+      cookies.extend(acceptance::encoded_cookies(
+        &encoder,
+        canary,
+        profile.session.key(),
+      )?);
+    }
+
+    let mut captures = vec![];
+    for cookie in cookies {
+      let path = format!("{}?name={}", scenarios::ACCEPT_ROUTE, encode(&cookie.name));
+      let header = format!("{}={}", cookie.name, encode(&cookie.value));
+      let (_, app) =
+        Self::request_app(container, address, &path, &[(COOKIE.as_str(), &header)]).await?;
+      captures.push(AcceptanceCapture {
+        rails_version: container.rails_version.clone(),
+        profile: container.profile.clone(),
+        accepted: cookie.accepted(&app.accepted),
+        passed: cookie.passed(&app.accepted),
+        read: app.accepted.into_iter().collect(),
+        cookie,
+      });
+    }
+    Ok(captures)
+  }
+
+  async fn query_rotation(
+    runtime: &dyn ContainerRuntime,
+    flow: &RotationFlow,
+    from: &RailsContainer,
+    to: &RailsContainer,
+    scenarios: &[Scenario],
+    timeout: Duration,
+  ) -> Result<RotationCapture, String> {
+    let address = |container: &RailsContainer| {
+      container
+        .address
+        .clone()
+        .ok_or_else(|| "Container has no published port".to_string())
+    };
+    runtime::wait_until_ready(runtime, &from.id, timeout).await?;
+    runtime::wait_until_ready(runtime, &to.id, timeout).await?;
+
+    let (set_cookies, app) = Self::request_app(from, &address(from)?, "/", &[]).await?;
+    let sent = Self::capture_all(set_cookies, scenarios, from, &app);
+    let cookie_header = cookie_header(&sent);
+
+    let (set_cookies, app) = Self::request_app(
+      to,
+      &address(to)?,
+      scenarios::READ_ROUTE,
+      &[(COOKIE.as_str(), &cookie_header)],
+    )
+    .await?;
+    let upgraded = Self::capture_all(set_cookies, scenarios, to, &app);
+    Ok(RotationCapture {
+      name: flow.name.clone(),
+      rails_version: to.rails_version.clone(),
+      from: from.profile.clone(),
+      to: to.profile.clone(),
+      sent,
+      read: app.cookies,
+      upgraded,
+    })
+  }
+
+  /// Requests a path of the app with the `Cookie` header `cookies`, returning
+  /// its `Set-Cookie` headers and report.
+  async fn request_app(
+    container: &RailsContainer,
+    address: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+  ) -> Result<(Vec<String>, AppReport), String> {
+    let rails_version = &container.rails_version;
+    let url = format!("http://{}{}", address, path);
+    // Rails only writes secure cookies over HTTPS, as seen behind a TLS proxy.
+    let mut request = reqwest::Client::new()
+      .get(&url)
+      .header("X-Forwarded-Proto", "https");
+    for (name, value) in headers {
+      request = request.header(*name, *value);
+    }
+    let response = request.send().await.map_err(|err| err.to_string())?;
+    let status = response.status();
+    let cookies = response
+      .headers()
+      .get_all(SET_COOKIE)
+      .iter()
+      .map(|cookie| decode(cookie.to_str().unwrap()).unwrap().to_string())
       .collect();
-    cookies
+    let body = response.text().await.map_err(|err| err.to_string())?;
+    if !status.is_success() {
+      return Err(format!("Server responded with {}", status));
+    }
+    let mut app: AppReport =
+      serde_json::from_str(&body).map_err(|_| format!("Unexpected body: {}", body))?;
+    if &app.version != rails_version {
+      return Err(format!("Wrong version body: {}", body));
+    }
+    if app.environment != container.profile.environment {
+      return Err(format!("Wrong environment body: {}", body));
+    }
+    if let Some(credentials) = app.credentials.as_mut() {
+      let decrypted = rails::credentials::decrypt(&credentials.master_key, &credentials.content);
+      credentials.secret_key_base = match decrypted {
+        Ok(yaml) => rails::credentials::secret_key_base(&yaml),
+        Err(err) => {
+          warn!(
+            "Could not decrypt the credentials of Rails v{}: {}",
+            rails_version, err
+          );
+          None
+        }
+      };
+      if credentials.secret_key_base.as_ref() != Some(&app.secret_key_base) {
+        warn!(
+          "Rails v{} does not use the secret_key_base of its credentials",
+          rails_version
+        );
+      }
+    }
+
+    Ok((cookies, app))
+  }
+
+  /// The failure of `container` with `error`, with the tail of its logs,
+  /// saved to the run directory.
+  async fn failure(
+    runtime: &dyn ContainerRuntime,
+    run_directory: &Path,
+    container: &RailsContainer,
+    error: String,
+  ) -> ContainerFailure {
+    let (logs, logs_path) = Self::fetch_logs(runtime, run_directory, container).await;
+    let tail = logs.len().saturating_sub(20);
+    ContainerFailure {
+      rails_version: container.rails_version.clone(),
+      profile: container.profile.clone(),
+      error,
+      logs_tail: logs[tail..].to_vec(),
+      logs_path,
+    }
+  }
+
+  /// Fetches the logs of a container and saves them, see [`Self::save_logs`].
+  async fn fetch_logs(
+    runtime: &dyn ContainerRuntime,
+    run_directory: &Path,
+    container: &RailsContainer,
+  ) -> (Vec<String>, Option<PathBuf>) {
+    let logs = runtime
+      .container_logs(&container.id)
+      .await
+      .unwrap_or_else(|err| {
+        vec![format!(
+          "Could not fetch container logs: {}",
+          err
+        )]
+      });
+    let logs_path = Self::save_logs(run_directory, container, &logs);
+    (logs, logs_path)
+  }
+
+  /// Writes the logs of a container to `<run_directory>/rails-v<version>-<profile>.log`.
+  fn save_logs(
+    run_directory: &Path,
+    container: &RailsContainer,
+    logs: &[String],
+  ) -> Option<PathBuf> {
+    let rails_version = &container.rails_version;
+    let path = run_directory.join(format!(
+      "rails-v{}-{}.log",
+      rails_version,
+      container.profile.label()
+    ));
+    let contents: String = logs
+      .iter()
+      .map(|line| format!("{}\n", line.trim_end()))
+      .collect();
+    let written =
+      std::fs::create_dir_all(run_directory).and_then(|_| std::fs::write(&path, contents));
+    match written {
+      Ok(_) => {
+        debug!(
+          "Saved logs of Rails v{} to {}",
+          rails_version,
+          path.display()
+        );
+        Some(path)
+      }
+      Err(err) => {
+        error!("Failed to save logs of Rails v{}: {}", rails_version, err);
+        None
+      }
+    }
   }
 
   pub async fn stop_containers(&self) {
-    let containers = self
+    let containers: Vec<String> = self
       .containers
       .iter()
-      .map(|(_, container_id)| container_id)
-      .cloned()
+      .map(|container| container.id.clone())
       .collect();
-    RailsCookiesMonster::drop_containers(containers).await;
+    containers
+      .iter()
+      .for_each(|container_id| self.cleanup.registry.untrack(container_id));
+    RailsCookiesMonster::drop_containers(self.runtime.clone(), containers).await;
+
+    if let Some(network) = self.network() {
+      match self.runtime.remove_network(&network).await {
+        Ok(_) => trace!("Removed network {}", network),
+        Err(err) => debug!("Kept network {}: {}", network, err),
+      }
+    }
   }
 
-  pub async fn drop_containers(containers: Vec<String>) {
+  pub async fn drop_containers(runtime: Arc<dyn ContainerRuntime>, containers: Vec<String>) {
     trace!("Dropping {} containers", containers.len());
     let tasks = containers.iter().map(|container_id| {
       let id_to_kill = container_id.clone();
+      let runtime = runtime.clone();
       tokio::spawn(async move {
-        // Do we really need to stop it if we remove it right after?
-        // docker::DOCKER.lock()
-        //   .await
-        //   .stop_container(&id_to_kill, Duration::from_secs(1))
-        //   .await
-        //   .unwrap();
-        // trace!("Stopped container {}", id_to_kill);
-        docker::DOCKER
-          .lock()
-          .await
-          .remove_container(&id_to_kill, Some(true), Some(true), None)
-          .await
-          .unwrap();
-        trace!("- Removed container: {}", id_to_kill);
+        let removal = runtime.remove_container(&id_to_kill).await;
+        match removal {
+          Ok(_) => trace!("- Removed container: {}", id_to_kill),
+          Err(err) => error!("- Failed to remove container {}: {}", id_to_kill, err),
+        }
       })
     });
     let _ = join_all(tasks).await;
   }
 }
+
+/// The internal network of the run of process `owner`, see [`RUN_LABEL`](runtime::RUN_LABEL).
+fn run_network(owner: &str) -> String {
+  format!("rails-cookies-monster-run-{}", owner)
+}
+
+/// The `Cookie` header sending captured cookies back to an app.
+///
+/// Values were URL-decoded when captured, Rails decodes them again.
+fn cookie_header(cookies: &[CapturedCookie]) -> String {
+  cookies
+    .iter()
+    .map(|cookie| {
+      let (name, value) = cookie.pair();
+      format!("{}={}", name, encode(value))
+    })
+    .join("; ")
+}
+
+/// Parses a memory size in bytes, with an optional `k`, `m` or `g` suffix.
+fn parse_memory(value: &str) -> Option<u64> {
+  let value = value.trim().to_lowercase();
+  let (number, unit) = match value.char_indices().last()? {
+    (index, 'k') => (&value[..index], 1 << 10),
+    (index, 'm') => (&value[..index], 1 << 20),
+    (index, 'g') => (&value[..index], 1 << 30),
+    _ => (value.as_str(), 1),
+  };
+  number.parse::<u64>().ok().map(|number| number * unit)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::sync::Arc;
+
+  use super::run_network;
+  use crate::acceptance::SentCookie;
+  use crate::rotation::rotation_suite;
+  use crate::runtime::fake::FakeRuntime;
+  use crate::runtime::RUN_LABEL;
+  use crate::scenarios::Jar;
+  use crate::RailsCookiesMonster;
+
+  /// A fake runtime serving the cookies captured in `cookies/`.
+  fn fixtures() -> FakeRuntime {
+    FakeRuntime::new().with_fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/cookies").as_ref())
+  }
+
+  /// A monster running on `runtime` the versions of `requirement`, once
+  /// `configure` set it up, with its containers started.
+  async fn started(
+    runtime: FakeRuntime,
+    requirement: &str,
+    configure: impl FnOnce(&mut RailsCookiesMonster),
+  ) -> RailsCookiesMonster {
+    std::env::set_var(
+      "RUN_DIRECTORY",
+      std::env::temp_dir().join("rails-cookies-monster"),
+    );
+    let mut monster = RailsCookiesMonster::with_runtime(Arc::new(runtime));
+    configure(&mut monster);
+    monster.add_version_requirement(requirement);
+    monster.build_base_image().await.unwrap();
+    monster.build_versions_images().await.unwrap();
+    monster.start_containers().await;
+    monster
+  }
+
+  #[tokio::test]
+  async fn query_containers() {
+    let runtime = fixtures().with_exit("8.0.0", 1);
+    let monster = started(runtime, ">=8.0.0, <=8.0.1", |_| {}).await;
+    let (captures, failures) = monster.query_containers().await;
+    monster.stop_containers().await;
+
+    assert_eq!(captures.len(), 1);
+    assert_eq!(captures[0].rails_version, "8.0.1");
+    assert_eq!(captures[0].profile.environment, "production");
+    assert_eq!(captures[0].secret_key_base, monster.secret);
+    assert_eq!(captures[0].cookies.len(), 4);
+    let cookie = |name: &str| {
+      let mut cookies = captures[0].cookies.iter();
+      cookies.find(|cookie| cookie.pair().0 == name).unwrap()
+    };
+    assert_eq!(cookie("encrypted").scenarios, ["encrypted"]);
+    assert_eq!(cookie("_cookie_monster_session").scenarios, ["session"]);
+    assert!(cookie("regular").scenarios.is_empty());
+    let encrypted = cookie("encrypted");
+    assert_eq!(encrypted.expected["encrypted"], monster.canary);
+    assert_eq!(encrypted.decoded.as_ref().unwrap(), &monster.canary);
+    assert_eq!(encrypted.attributes["path"], "/");
+    assert_eq!(encrypted.attributes["httponly"], true);
+    let session = cookie("_cookie_monster_session");
+    let scenario = monster.scenarios.iter().find(|s| s.name == "session");
+    let decoded = session.decoded_for(scenario.unwrap());
+    assert_eq!(decoded, Some(&session.expected["session"]));
+    assert!(session.decoded.as_ref().unwrap()["session_id"].is_string());
+    assert!(captures[0].cookies.iter().all(|cookie| !cookie.rejected));
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].error, "Container exited with code 1");
+  }
+
+  #[tokio::test]
+  async fn sweep_networks() {
+    let monster = started(fixtures(), "=8.0.1", |monster| {
+      monster.isolated = true;
+    })
+    .await;
+    let pid = std::process::id().to_string();
+    let own = (run_network(&pid), pid.clone());
+    let networks = monster.runtime.labelled_networks(RUN_LABEL).await;
+    assert_eq!(networks.unwrap(), std::slice::from_ref(&own));
+
+    // Above the largest PID Linux hands out, and a label from before PIDs.
+    for (network, owner) in [
+      ("stale", "4194305"),
+      ("legacy", "true"),
+    ] {
+      let labels = HashMap::from([(RUN_LABEL.to_string(), owner.to_string())]);
+      monster
+        .runtime
+        .create_network(network, &labels)
+        .await
+        .unwrap();
+    }
+    monster.sweep_containers().await;
+    let networks = monster.runtime.labelled_networks(RUN_LABEL).await;
+    assert_eq!(networks.unwrap(), [own]);
+    monster.stop_containers().await;
+  }
+
+  #[tokio::test]
+  async fn query_flows_failure_logs() {
+    let runtime = FakeRuntime::new().with_exit("8.0.1", 1);
+    let monster = started(runtime, "=8.0.1", |monster| {
+      monster.session_flows = true;
+    })
+    .await;
+    let (flows, failures) = monster.query_flows().await;
+    monster.stop_containers().await;
+
+    assert!(flows.is_empty());
+    assert_eq!(failures[0].error, "Container exited with code 1");
+    assert_eq!(failures[0].logs_tail, ["Fake boot failure, exiting with 1"]);
+    assert!(failures[0].logs_path.as_ref().unwrap().exists());
+  }
+
+  #[tokio::test]
+  async fn query_encrypted_files() {
+    let monster = started(FakeRuntime::new(), "=8.0.1", |monster| {
+      monster.encrypted_files = true;
+    })
+    .await;
+    let (captures, failures) = monster.query_containers().await;
+    monster.stop_containers().await;
+
+    assert!(failures.is_empty());
+    let encrypted_file = captures[0].encrypted_file.as_ref().unwrap();
+    assert_eq!(encrypted_file.read.as_ref(), Some(&encrypted_file.contents));
+    let canary = format!("canary: {}\n", monster.canary);
+    assert_eq!(encrypted_file.decrypted, Some(canary));
+  }
+
+  #[tokio::test]
+  async fn query_rotations() {
+    let monster = started(fixtures(), "=8.0.1", |monster| {
+      monster.rotations = rotation_suite(&["secret"], &monster.secret);
+    })
+    .await;
+    let (rotations, failures) = monster.query_rotations().await;
+    monster.stop_containers().await;
+
+    assert!(failures.is_empty());
+    assert_eq!(rotations[0].sent.len(), 4);
+    assert_eq!(rotations[0].read["encrypted"], monster.canary);
+    assert_eq!(rotations[0].read["session"], monster.canary);
+  }
+
+  #[tokio::test]
+  async fn query_flows() {
+    let monster = started(FakeRuntime::new(), "=8.0.1", |monster| {
+      monster.session_flows = true;
+    })
+    .await;
+    let (flows, failures) = monster.query_flows().await;
+    monster.stop_containers().await;
+
+    assert!(failures.is_empty());
+    let session = |step: usize| flows[0].steps[step].session.clone().unwrap();
+    assert_eq!(session(0)["written"]["flow"], monster.canary);
+    assert_eq!(session(1)["read"]["flow"], monster.canary);
+    let mutated = format!("{}-mutated", monster.canary);
+    assert_eq!(session(1)["written"]["flow"], mutated);
+    assert!(session(2)["written"].get("flow").is_none());
+    assert_eq!(flows[0].steps[3].jar.header(), "");
+  }
+
+  #[tokio::test]
+  async fn query_acceptance() {
+    let monster = started(FakeRuntime::new(), "=8.0.1", |monster| {
+      monster.encoded_cookies = true;
+      monster.sent_cookies = vec![SentCookie {
+        name: "forged".to_string(),
+        value: "Zm9yZ2Vk--0000000000000000000000000000000000000000".to_string(),
+        jar: Jar::Signed,
+        expected: None,
+      }];
+    })
+    .await;
+    let (captures, failures) = monster.query_acceptance().await;
+    monster.stop_containers().await;
+
+    assert!(failures.is_empty());
+    assert_eq!(captures.len(), 6);
+    assert!(!captures[0].accepted);
+    assert!(captures[1..].iter().all(|capture| capture.passed));
+  }
+}
@@ -1,23 +1,53 @@
+use std::collections::BTreeMap;
 use std::env;
 
-use rails_cookies_monster::RailsCookiesMonster;
+use rails_cookies_monster::acceptance::AcceptanceCapture;
+use rails_cookies_monster::flows::FlowCapture;
+use rails_cookies_monster::rotation::RotationCapture;
+use rails_cookies_monster::{Capture, ContainerFailure, RailsCookiesMonster};
 use std::io::Write;
 
+fn usage(program: &str) {
+  eprintln!("Usage: {} <RAILS_VERSION_TAG>", program);
+  eprintln!("       {} serve <RAILS_VERSION_TAG>", program);
+  eprintln!("       {} query [RAILS_VERSION_TAG]", program);
+  eprintln!("       {} list", program);
+  eprintln!("       {} restart <RAILS_VERSION>", program);
+  eprintln!("       {} stop", program);
+}
+
 #[tokio::main]
 async fn main() {
-  // Extract RAILS_VERSION_TAG from the first argument
+  // Extract the command, or RAILS_VERSION_TAG, from the first argument
   let args: Vec<String> = env::args().collect();
   if args.len() < 2 {
-    eprintln!("Usage: {} <RAILS_VERSION_TAG>", args[0]);
+    usage(&args[0]);
     std::process::exit(1);
   }
   env_logger::init();
 
+  match (args[1].as_str(), args.get(2)) {
+    ("serve", Some(requirement)) => serve(requirement).await,
+    ("query", requirement) => query(requirement).await,
+    ("list", None) => list().await,
+    ("restart", Some(rails_version)) => restart(rails_version).await,
+    ("stop", None) => stop().await,
+    ("serve" | "list" | "restart" | "stop", _) => {
+      usage(&args[0]);
+      std::process::exit(1);
+    }
+    (requirement, _) => run(requirement).await,
+  }
+}
+
+/// Builds the images of the versions matching `requirement`.
+async fn setup(requirement: &str) -> RailsCookiesMonster {
   // Set up Monster
   let mut monster = RailsCookiesMonster::new();
-  monster.add_version_requirement(&args[1]);
+  monster.cleanup_on_signal();
+  monster.add_version_requirement(requirement);
   if monster.ruby_versions().is_empty() {
-    eprintln!("Error: No version matching requirement {}", args[1]);
+    eprintln!("Error: No version matching requirement {}", requirement);
     std::process::exit(1);
   }
 
@@ -38,12 +68,228 @@ async fn main() {
     eprintln!("Exiting...");
     std::process::exit(1);
   }
+  monster
+}
+
+/// Starts the matching versions, collects their cookies and removes them.
+async fn run(requirement: &str) {
+  let mut monster = setup(requirement).await;
+  monster.sweep_containers().await;
   monster.start_containers().await;
 
-  let cookies = monster.query_containers().await;
+  let (captures, mut failures) = monster.query_containers().await;
+  let (rotations, rotation_failures) = monster.query_rotations().await;
+  let (flows, flow_failures) = monster.query_flows().await;
+  let (acceptance, acceptance_failures) = monster.query_acceptance().await;
+
+  monster.stop_containers().await;
+
+  failures.extend(rotation_failures);
+  failures.extend(flow_failures);
+  failures.extend(acceptance_failures);
+  report_failures(failures);
+  write_captures(&monster, &captures);
+  write_secrets(&monster, &captures);
+  write_rotations(&monster, &rotations);
+  write_flows(&monster, &flows);
+  write_acceptance(&monster, &acceptance);
+  write_cookie_jar(&monster, captures);
+}
+
+/// Starts the matching versions and leaves them running for `query`.
+async fn serve(requirement: &str) {
+  if env::var("RAILS_RUNNER").as_deref() == Ok("native") {
+    eprintln!("Error: serve needs the docker runner, native servers stop with the process");
+    std::process::exit(1);
+  }
+  let mut monster = setup(requirement).await;
+  if let Ok(state) = rails_cookies_monster::daemon::load() {
+    eprintln!(
+      "Error: {} containers are already served, stop them first",
+      state.containers.len()
+    );
+    std::process::exit(1);
+  }
+  monster.daemon = true;
+  monster.start_containers().await;
+  if let Err(err) = monster.save_daemon_state() {
+    eprintln!("Error: Could not save daemon state: {}", err);
+    monster.stop_containers().await;
+    std::process::exit(1);
+  }
+  println!("Serving {} Rails versions", monster.rails_versions().len());
+}
+
+/// Loads the containers left running by `serve`, or exits.
+fn load_daemon(requirement: Option<&String>) -> RailsCookiesMonster {
+  let mut monster = RailsCookiesMonster::new();
+  if let Some(requirement) = requirement {
+    monster.add_version_requirement(requirement);
+  }
+  if let Err(err) = monster.load_daemon_state() {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
+  }
+  monster
+}
+
+/// Collects the cookies of the containers left running by `serve`.
+async fn query(requirement: Option<&String>) {
+  let monster = load_daemon(requirement);
+  let (captures, mut failures) = monster.query_containers().await;
+  let (rotations, rotation_failures) = monster.query_rotations().await;
+  let (flows, flow_failures) = monster.query_flows().await;
+  let (acceptance, acceptance_failures) = monster.query_acceptance().await;
+  failures.extend(rotation_failures);
+  failures.extend(flow_failures);
+  failures.extend(acceptance_failures);
+  report_failures(failures);
+  write_captures(&monster, &captures);
+  write_secrets(&monster, &captures);
+  write_rotations(&monster, &rotations);
+  write_flows(&monster, &flows);
+  write_acceptance(&monster, &acceptance);
+  write_cookie_jar(&monster, captures);
+}
+
+async fn list() {
+  let monster = load_daemon(None);
+  for (container, state) in monster.list_containers().await {
+    let status = match state {
+      Ok(state) if state.running => "running".to_string(),
+      Ok(state) => format!("exited ({})", state.exit_code),
+      Err(err) => format!("unknown ({})", err),
+    };
+    println!(
+      "rails-v{}\t{}\t{}\t{}\t{}",
+      container.rails_version,
+      container.profile.label(),
+      container.id,
+      container.address.unwrap_or_default(),
+      status
+    );
+  }
+}
+
+async fn restart(rails_version: &str) {
+  let mut monster = load_daemon(None);
+  let restarted = monster.restart_container(rails_version).await;
+  if let Err(err) = restarted.and_then(|_| monster.save_daemon_state()) {
+    eprintln!("Error: Could not restart Rails v{}: {}", rails_version, err);
+    std::process::exit(1);
+  }
+}
 
+async fn stop() {
+  let monster = load_daemon(None);
   monster.stop_containers().await;
+  if let Err(err) = rails_cookies_monster::daemon::clear() {
+    eprintln!("Error: Could not clear daemon state: {}", err);
+    std::process::exit(1);
+  }
+}
+
+fn report_failures(failures: Vec<ContainerFailure>) {
+  if failures.is_empty() {
+    return;
+  }
+  eprintln!("Failed to query {} rails containers", failures.len());
+  for failure in failures {
+    eprintln!(
+      "- Failed to query rails-v{} ({}): {}",
+      failure.rails_version,
+      failure.profile.label(),
+      failure.error
+    );
+    if let Some(logs_path) = failure.logs_path {
+      eprintln!("  Logs saved to {}", logs_path.display());
+    }
+    for line in failure.logs_tail {
+      eprintln!("  | {}", line.trim_end());
+    }
+  }
+}
+
+/// Saves the captures, with the configuration that produced them, to the run directory.
+fn write_captures(monster: &RailsCookiesMonster, captures: &[Capture]) {
+  write_json(monster, "captures.json", captures);
+}
 
+/// Saves the captures keyed by the secret the app used, if the run has several.
+fn write_secrets(monster: &RailsCookiesMonster, captures: &[Capture]) {
+  if captures
+    .iter()
+    .all(|capture| capture.profile.secret.is_none())
+  {
+    return;
+  }
+  let mut secrets: BTreeMap<&str, Vec<&Capture>> = BTreeMap::new();
+  for capture in captures {
+    secrets
+      .entry(&capture.secret_key_base)
+      .or_default()
+      .push(capture);
+  }
+  write_json(monster, "secrets.json", &secrets);
+}
+
+/// Saves the cookies of the rotation flows to the run directory, if any ran.
+fn write_rotations(monster: &RailsCookiesMonster, rotations: &[RotationCapture]) {
+  if !rotations.is_empty() {
+    write_json(monster, "rotations.json", rotations);
+  }
+}
+
+/// Saves the steps of the session flows to the run directory, if any ran.
+fn write_flows(monster: &RailsCookiesMonster, flows: &[FlowCapture]) {
+  if !flows.is_empty() {
+    write_json(monster, "flows.json", flows);
+  }
+}
+
+/// Saves what Rails read from the cookies of `ACCEPT_COOKIES` and of the
+/// encoder suite to the run directory, and prints whether each version
+/// passed, if any were sent.
+fn write_acceptance(monster: &RailsCookiesMonster, acceptance: &[AcceptanceCapture]) {
+  if acceptance.is_empty() {
+    return;
+  }
+  write_json(monster, "acceptance.json", acceptance);
+  for capture in acceptance {
+    let verdict = match (capture.passed, capture.accepted) {
+      (true, _) => "pass",
+      (false, true) => "fail (unexpected value)",
+      (false, false) => "fail (rejected)",
+    };
+    println!(
+      "rails-v{}\t{}\t{}\t{}",
+      capture.rails_version,
+      capture.profile.label(),
+      capture.cookie.name,
+      verdict
+    );
+  }
+}
+
+fn write_json<T: serde::Serialize + ?Sized>(
+  monster: &RailsCookiesMonster,
+  file_name: &str,
+  value: &T,
+) {
+  let path = monster.run_directory.join(file_name);
+  let written = serde_json::to_string_pretty(value)
+    .map_err(|err| err.to_string())
+    .and_then(|contents| {
+      std::fs::create_dir_all(&monster.run_directory)
+        .and_then(|_| std::fs::write(&path, contents))
+        .map_err(|err| err.to_string())
+    });
+  if let Err(err) = written {
+    eprintln!("Error: Could not write {}: {}", path.display(), err);
+  }
+}
+
+fn write_cookie_jar(monster: &RailsCookiesMonster, captures: Vec<Capture>) {
   // Write cookies to a curl cookie jar file
   let mut jar = std::fs::File::create("cookies.txt").expect("Could not create cookie jar file");
   // Write the Netscape HTTP Cookie File header
@@ -54,6 +300,17 @@ async fn main() {
   writeln!(jar, "# File generated by rails-cookies-monster with:").unwrap();
   writeln!(jar, "# - SECRET_KEY_BASE: {}", monster.secret).unwrap();
   writeln!(jar, "# - CANARY_VALUE: {}", monster.canary).unwrap();
+  writeln!(jar, "# Effective secrets:").unwrap();
+  for capture in &captures {
+    writeln!(
+      jar,
+      "# - rails-{}-{}: {}",
+      capture.rails_version,
+      capture.profile.label(),
+      capture.secret_key_base
+    )
+    .unwrap();
+  }
   writeln!(jar).unwrap();
   writeln!(jar).unwrap();
 
@@ -61,13 +318,23 @@ async fn main() {
    */
   // Write each cookie in the Netscape format
   // Format: HOST_DOMAIN SUBDOMAIN_FLAG PATH SECURE_FLAG TIMESTAMP COOKIE_NAME COOKIE_VALUE
-  for (version, cookie) in cookies {
+  for (capture, cookie) in captures
+    .iter()
+    .flat_map(|capture| capture.cookies.iter().map(move |cookie| (capture, cookie)))
+  {
+    let (name, value) = cookie.pair();
+    // Scenarios requested one by one may set the same cookie, e.g. the session.
+    let name = match monster.each_scenario {
+      true => format!("{}-{}", cookie.scenarios.join("+"), name),
+      false => name.to_string(),
+    };
     writeln!(
       jar,
-      "localhost:3000\tTRUE\t/\tFALSE\t0\trails-{}-{}\t{}",
-      version,
-      cookie.split_once(';').unwrap().0.split_once('=').unwrap().0,
-      cookie.split_once(';').unwrap().0.split_once('=').unwrap().1
+      "localhost:3000\tTRUE\t/\tFALSE\t0\trails-{}-{}-{}\t{}",
+      capture.rails_version,
+      capture.profile.label(),
+      name,
+      value
     )
     .unwrap();
   }
@@ -79,8 +346,18 @@ async fn main() {
     // println!("Version: {}", version);
     let (cookie_name, cookie_value) = cookie.split_once(';').unwrap().0.split_once('=').unwrap();
     // println!(" => COOKIES: _{}", cookie_name);
-    let message =
-      rails::decipher_cookie(&version, cookie_value).expect("Could not decipher cookie");
+    let envelope =
+      rails::decipher_cookie(
+        &version,
+        Serializer::Json,
+        CookieCipher::Aes256Gcm,
+        KeyGenerator::for_version(&version),
+        &CookieSalts::default(),
+        cookie_name,
+        cookie_value,
+      )
+        .expect("Could not decipher cookie");
+    let message = envelope.message.to_string();
     // println!(" => MESSAGE: _{}", message);
 
     match cookie_name {
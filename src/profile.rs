@@ -0,0 +1,518 @@
+use itertools::Itertools;
+use log::warn;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::rails::crypto::{CookieCipher, CookieSalts, KeyDigest, KeyGenerator};
+use crate::rails::encoder::CookieEncoder;
+use crate::rails::serializer::Serializer;
+use crate::rotation::Rotation;
+use crate::scenarios::{ruby_string, SESSION_COOKIE};
+
+/// The environments a Rails app can be booted in.
+pub const ENVIRONMENTS: [&str; 3] = [
+  "production",
+  "development",
+  "test",
+];
+
+/// Where the configuration of a profile is written in the Rails app.
+///
+/// Initializers load in alphabetical order, this one after the generated ones.
+pub const INITIALIZER_PATH: &str = "config/initializers/rails_cookies_monster.rb";
+
+/// The configuration a Rails app is booted with.
+///
+/// Every Rails version of a run is booted once per profile, and the cookies
+/// captured are labeled with the profile that produced them.
+///
+/// * environment: The `RAILS_ENV` of the app
+/// * serializer: The `config.action_dispatch.cookies_serializer` of the app
+/// * cipher: How the app encrypts its encrypted cookies
+/// * digest: The `config.active_support.key_generator_hash_digest_class` of
+///   the app, the default of its version if unset
+/// * secret: The `SECRET_KEY_BASE` of the app, the one of the run if unset
+/// * credentials: Whether the app reads its secret from the encrypted
+///   credentials Rails generated for it, booted without `SECRET_KEY_BASE`
+/// * salts: The salts the app derives the keys of its cookies with
+/// * session: The options of the session cookie
+/// * rotations: The old configurations whose cookies the app rewrites
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+  pub environment: String,
+  pub serializer: Serializer,
+  pub cipher: CookieCipher,
+  pub digest: Option<KeyDigest>,
+  pub secret: Option<String>,
+  pub credentials: bool,
+  pub salts: CookieSalts,
+  pub session: SessionStore,
+  pub rotations: Vec<Rotation>,
+}
+
+impl Default for Profile {
+  fn default() -> Self {
+    Self {
+      environment: "production".to_string(),
+      serializer: Serializer::default(),
+      cipher: CookieCipher::default(),
+      digest: None,
+      secret: None,
+      credentials: false,
+      salts: CookieSalts::default(),
+      session: SessionStore::default(),
+      rotations: vec![],
+    }
+  }
+}
+
+impl Profile {
+  /// A short name for the profile, used in container names and outputs.
+  ///
+  /// Settings left to their default are omitted, e.g. `production` or
+  /// `production-marshal-aes-128-gcm-sha1`.
+  pub fn label(&self) -> String {
+    let mut label = self.environment.clone();
+    if self.serializer != Serializer::default() {
+      label.push('-');
+      label.push_str(self.serializer.name());
+    }
+    if self.cipher != CookieCipher::default() {
+      label.push('-');
+      label.push_str(self.cipher.name());
+    }
+    if let Some(digest) = self.digest {
+      label.push('-');
+      label.push_str(digest.name());
+    }
+    if let Some(secret) = &self.secret {
+      label.push('-');
+      label.push_str(&secret_label(secret));
+    }
+    if self.credentials {
+      label.push_str("-credentials");
+    }
+    if self.salts != CookieSalts::default() {
+      let salts = self.salts.settings().map(|(_, salt)| salt).join("\n");
+      label.push_str("-salts-");
+      label.push_str(&short_digest(&salts));
+    }
+    if self.session != SessionStore::default() {
+      let session = serde_json::to_string(&self.session).unwrap_or_default();
+      label.push_str("-session-");
+      label.push_str(&short_digest(&session));
+    }
+    for rotation in &self.rotations {
+      label.push('-');
+      label.push_str(&rotation.label());
+    }
+    label
+  }
+
+  /// The `KEY=value` environment variables configuring the app.
+  pub fn env(&self) -> Vec<String> {
+    vec![format!(
+      "RAILS_ENV={}",
+      self.environment
+    )]
+  }
+
+  /// Whether `rails_version` can be booted with the profile.
+  ///
+  /// The key generator digest can only be set from Rails 7.0, and cookies
+  /// rotated from Rails 5.2.
+  pub fn supports(&self, rails_version: &str) -> bool {
+    let mut parts = rails_version
+      .split('.')
+      .map(|part| part.parse::<u64>().unwrap_or(0));
+    let version = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    let sets_digest = self.digest.is_some()
+      || self
+        .rotations
+        .iter()
+        .any(|rotation| rotation.digest.is_some());
+    self.serializer.supports(rails_version)
+      && (!sets_digest || version >= (7, 0))
+      && (self.rotations.is_empty() || version >= (5, 2))
+  }
+
+  /// The key generator of the app, as booted on `rails_version`.
+  pub fn key_generator(&self, rails_version: &str) -> KeyGenerator {
+    match self.digest {
+      Some(digest) => KeyGenerator::new(digest, KeyGenerator::ITERATIONS),
+      None => KeyGenerator::for_version(rails_version),
+    }
+  }
+
+  /// The encoder writing cookies as the app does, booted on `rails_version`
+  /// with `secret_key_base`.
+  pub fn encoder(&self, rails_version: &str, secret_key_base: &str) -> CookieEncoder {
+    CookieEncoder {
+      serializer: self.serializer,
+      cipher: self.cipher,
+      key_generator: self.key_generator(rails_version),
+      salts: self.salts.clone(),
+      ..CookieEncoder::new(rails_version, secret_key_base)
+    }
+  }
+
+  /// The Ruby initializer applying the profile to the app.
+  pub fn initializer(&self) -> String {
+    let mut ruby = format!(
+      "# Generated by rails-cookies-monster for the {} profile.\n",
+      self.label()
+    );
+    let mut config = |setting: &str, value: String| {
+      ruby.push_str(&format!(
+        "Rails.application.config.action_dispatch.{} = {}\n",
+        setting, value
+      ));
+    };
+    config("cookies_serializer", format!(":{}", self.serializer.name()));
+    config(
+      "use_authenticated_cookie_encryption",
+      self.cipher.is_authenticated().to_string(),
+    );
+    if self.cipher.is_authenticated() {
+      config(
+        "encrypted_cookie_cipher",
+        format!("{:?}", self.cipher.cipher()),
+      );
+    }
+    let default_salts = CookieSalts::default();
+    for ((setting, salt), (_, default)) in self
+      .salts
+      .settings()
+      .into_iter()
+      .zip(default_salts.settings())
+    {
+      if salt != default {
+        config(setting, ruby_string(salt));
+      }
+    }
+    if let Some(digest) = self.digest {
+      ruby.push_str(&format!(
+        "Rails.application.config.active_support.key_generator_hash_digest_class = {}\n",
+        digest.ruby_class()
+      ));
+    }
+    if self.session != SessionStore::default() {
+      ruby.push_str(&self.session.initializer());
+    }
+    for rotation in &self.rotations {
+      ruby.push_str(&rotation.initializer(self.cipher));
+    }
+    ruby
+  }
+}
+
+/// The options of the session cookie, set with
+/// `config.session_store :cookie_store`, Rails defaults when missing.
+///
+/// * key: The name of the session cookie, `_cookie_monster_session` by default
+/// * domain: The domain of the cookie, `all` for every subdomain of the host
+/// * path: The path of the cookie
+/// * same_site: One of `lax`, `strict` or `none`
+/// * secure: Whether the cookie is only sent over HTTPS
+/// * httponly: Whether the cookie is hidden from JavaScript
+/// * expire_after: Seconds the cookie lives for, a browser session if unset
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::profile::SessionStore;
+///
+/// let session = SessionStore {
+///   key: Some("_app_session".to_string()),
+///   domain: Some("all".to_string()),
+///   secure: Some(true),
+///   expire_after: Some(3600),
+///   ..SessionStore::default()
+/// };
+/// assert_eq!(session.key(), "_app_session");
+/// assert_eq!(
+///   session.initializer(),
+///   "Rails.application.config.session_store :cookie_store, key: \"_app_session\", \
+///    domain: :all, secure: true, expire_after: 3600.seconds\n"
+/// );
+/// ```
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionStore {
+  pub key: Option<String>,
+  pub domain: Option<String>,
+  pub path: Option<String>,
+  pub same_site: Option<String>,
+  pub secure: Option<bool>,
+  pub httponly: Option<bool>,
+  pub expire_after: Option<u64>,
+}
+
+impl SessionStore {
+  /// The name of the session cookie.
+  pub fn key(&self) -> &str {
+    self.key.as_deref().unwrap_or(SESSION_COOKIE)
+  }
+
+  /// The Ruby statement configuring the session store.
+  pub fn initializer(&self) -> String {
+    let mut options = vec![];
+    if let Some(key) = &self.key {
+      options.push(format!("key: {}", ruby_string(key)));
+    }
+    match self.domain.as_deref() {
+      Some("all") => options.push("domain: :all".to_string()),
+      Some(domain) => options.push(format!("domain: {}", ruby_string(domain))),
+      None => {}
+    }
+    if let Some(path) = &self.path {
+      options.push(format!("path: {}", ruby_string(path)));
+    }
+    if let Some(same_site) = &self.same_site {
+      options.push(format!("same_site: :{}", same_site));
+    }
+    if let Some(secure) = self.secure {
+      options.push(format!("secure: {}", secure));
+    }
+    if let Some(httponly) = self.httponly {
+      options.push(format!("httponly: {}", httponly));
+    }
+    if let Some(expire_after) = self.expire_after {
+      options.push(format!("expire_after: {}.seconds", expire_after));
+    }
+    let mut ruby = String::from("Rails.application.config.session_store :cookie_store");
+    for option in options {
+      ruby.push_str(", ");
+      ruby.push_str(&option);
+    }
+    ruby.push('\n');
+    ruby
+  }
+}
+
+/// A short name for a secret, the start of its SHA256 digest, e.g. `secret-1a2b3c4d`.
+pub fn secret_label(secret: &str) -> String {
+  format!("secret-{}", short_digest(secret))
+}
+
+/// The secrets of the secret suite (`SECRET_SUITE`), edge cases of the input
+/// of key derivation: a one-character secret, 128 hex characters as generated
+/// by `rails secret`, spaces, unicode and shell metacharacters.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::profile::secret_suite;
+///
+/// let secrets = secret_suite();
+/// assert_eq!(secrets[0].len(), 1);
+/// assert_eq!(secrets[1].len(), 128);
+/// assert!(secrets.iter().all(|secret| !secret.contains('\n')));
+/// ```
+pub fn secret_suite() -> Vec<String> {
+  let hex: String = digest::digest(&digest::SHA512, b"rails-cookies-everywhere")
+    .as_ref()
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect();
+  vec![
+    "s".to_string(),
+    hex,
+    "rails cookies everywhere".to_string(),
+    "rails-cookies-\u{e9}verywhere-\u{1f36a}".to_string(),
+    "$(echo monster);`id` | & > 'quoted' \"double\" \\ *?".to_string(),
+  ]
+}
+
+/// The first 8 hex digits of the SHA256 digest of `value`.
+fn short_digest(value: &str) -> String {
+  let digest = digest::digest(&digest::SHA256, value.as_bytes());
+  digest.as_ref()[..4]
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect()
+}
+
+/// The cookie salts of a run.
+///
+/// Each salt is read from the environment variable named after its setting,
+/// e.g. `AUTHENTICATED_ENCRYPTED_COOKIE_SALT`, and defaults to the one of Rails.
+pub fn salts() -> CookieSalts {
+  let mut salts = CookieSalts::default();
+  for (setting, salt) in [
+    ("SIGNED_COOKIE_SALT", &mut salts.signed),
+    ("ENCRYPTED_COOKIE_SALT", &mut salts.encrypted),
+    ("ENCRYPTED_SIGNED_COOKIE_SALT", &mut salts.encrypted_signed),
+    (
+      "AUTHENTICATED_ENCRYPTED_COOKIE_SALT",
+      &mut salts.authenticated_encrypted,
+    ),
+  ] {
+    if let Ok(value) = std::env::var(setting) {
+      *salt = value;
+    }
+  }
+  salts
+}
+
+/// The secrets every version of a run is booted with.
+///
+/// They are read from `SECRET_KEY_BASES`, one per line as secrets may contain
+/// commas, extended with the [`secret_suite`] when `SECRET_SUITE` is set. The
+/// `SECRET_KEY_BASE` of the run is `None`, and the only one by default.
+pub fn secrets() -> Vec<Option<String>> {
+  let run_secret = std::env::var("SECRET_KEY_BASE").ok();
+  let mut secrets: Vec<String> = match std::env::var("SECRET_KEY_BASES") {
+    Ok(values) => values
+      .lines()
+      .filter(|secret| !secret.is_empty())
+      .map(str::to_string)
+      .collect(),
+    Err(_) => run_secret.iter().cloned().collect(),
+  };
+  if std::env::var("SECRET_SUITE").is_ok() {
+    secrets.extend(secret_suite());
+  }
+  let secrets = secrets
+    .into_iter()
+    .map(|secret| (Some(&secret) != run_secret.as_ref()).then_some(secret))
+    .unique()
+    .collect_vec();
+  match secrets.is_empty() {
+    true => vec![None],
+    false => secrets,
+  }
+}
+
+/// The session store of a run.
+///
+/// Options are read from `SESSION_KEY`, `SESSION_DOMAIN`, `SESSION_PATH`,
+/// `SESSION_SAME_SITE`, `SESSION_SECURE`, `SESSION_HTTPONLY` and
+/// `SESSION_EXPIRE_AFTER`, invalid values are ignored.
+pub fn session_store() -> SessionStore {
+  fn var<T>(key: &str, parse: impl Fn(&str) -> Result<T, String>) -> Option<T> {
+    let value = std::env::var(key).ok()?;
+    parse(&value)
+      .inspect_err(|err| warn!("Ignoring {}: {}", key, err))
+      .ok()
+  }
+  let text = |value: &str| Ok(value.to_string());
+  let flag = |value: &str| {
+    value
+      .parse::<bool>()
+      .map_err(|_| format!("Not a boolean: {}", value))
+  };
+  SessionStore {
+    key: var("SESSION_KEY", text),
+    domain: var("SESSION_DOMAIN", text),
+    path: var("SESSION_PATH", text),
+    same_site: var("SESSION_SAME_SITE", |same_site| {
+      [
+        "lax",
+        "strict",
+        "none",
+      ]
+      .contains(&same_site)
+      .then(|| same_site.to_string())
+      .ok_or_else(|| format!("Unknown same_site: {}", same_site))
+    }),
+    secure: var("SESSION_SECURE", flag),
+    httponly: var("SESSION_HTTPONLY", flag),
+    expire_after: var("SESSION_EXPIRE_AFTER", |seconds| {
+      seconds
+        .parse()
+        .map_err(|_| format!("Not a number of seconds: {}", seconds))
+    }),
+  }
+}
+
+/// The profiles of a run, every environment with every serializer, cipher,
+/// key generator digest and [`secrets`].
+///
+/// With `SECRET_SOURCES=env,credentials`, production apps are also booted
+/// reading their secret from their credentials: development and test generate
+/// theirs whatever the credentials say.
+///
+/// Environments are read from `RAILS_ENVIRONMENTS`, serializers from
+/// `COOKIE_SERIALIZERS`, ciphers from `COOKIE_CIPHERS` and digests from
+/// `KEY_GENERATOR_DIGESTS`, comma-separated lists defaulting to `production`,
+/// `json`, `aes-256-gcm` and the `default` digest of each version only. All
+/// share the [`salts`] and [`session_store`] of the run.
+pub fn profiles() -> Vec<Profile> {
+  let default = Profile::default();
+  let salts = salts();
+  let session = session_store();
+  let sources = list(
+    "SECRET_SOURCES",
+    default.credentials,
+    |source| match source {
+      "env" => Ok(false),
+      "credentials" => Ok(true),
+      source => Err(format!("Unknown secret source: {}", source)),
+    },
+  );
+  let environments = list("RAILS_ENVIRONMENTS", default.environment, |environment| {
+    ENVIRONMENTS
+      .contains(&environment)
+      .then(|| environment.to_string())
+      .ok_or_else(|| format!("Unknown Rails environment: {}", environment))
+  });
+  let serializers = list("COOKIE_SERIALIZERS", default.serializer, str::parse);
+  let ciphers = list("COOKIE_CIPHERS", default.cipher, str::parse);
+  let digests = list(
+    "KEY_GENERATOR_DIGESTS",
+    default.digest,
+    |digest| match digest {
+      "default" => Ok(None),
+      digest => digest.parse().map(Some),
+    },
+  );
+  environments
+    .into_iter()
+    .cartesian_product(serializers)
+    .cartesian_product(ciphers)
+    .cartesian_product(digests)
+    .cartesian_product(secrets())
+    .cartesian_product(sources)
+    .map(
+      |(((((environment, serializer), cipher), digest), secret), credentials)| Profile {
+        environment,
+        serializer,
+        cipher,
+        digest,
+        // The secret of the credentials is the one Rails generated.
+        secret: secret.filter(|_| !credentials),
+        credentials,
+        salts: salts.clone(),
+        session: session.clone(),
+        ..Profile::default()
+      },
+    )
+    .filter(|profile| !profile.credentials || profile.environment == "production")
+    .unique()
+    .collect()
+}
+
+/// The distinct valid values of a comma-separated environment variable, or
+/// `default` when it is unset or has none.
+fn list<T: Clone + PartialEq>(
+  key: &str,
+  default: T,
+  parse: impl Fn(&str) -> Result<T, String>,
+) -> Vec<T> {
+  let Ok(values) = std::env::var(key) else {
+    return vec![default];
+  };
+  let mut parsed = vec![];
+  for value in values.split(',').map(str::trim) {
+    match parse(value) {
+      Ok(value) if !parsed.contains(&value) => parsed.push(value),
+      Ok(_) => {}
+      Err(err) => warn!("Ignoring a value of {}: {}", key, err),
+    }
+  }
+  if parsed.is_empty() {
+    warn!("No valid value in {}, using the default", key);
+    parsed.push(default);
+  }
+  parsed
+}
@@ -0,0 +1,90 @@
+use serde_json::Value;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::crypto::{decode_hex, decrypt_aes_gcm, encrypt_aes_gcm};
+use super::marshal;
+
+/// Decrypts `config/credentials.yml.enc` with the key of `config/master.key`,
+/// or any encrypted file of `Rails.application.encrypted` with its key.
+///
+/// Rails encrypts these files as `ActiveSupport::EncryptedFile`, with
+/// `aes-128-gcm` and the key itself, a 32-character hex string, and
+/// serializes their contents with Marshal. The contents, YAML for
+/// credentials, are returned as is.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::credentials;
+///
+/// // Synthetic, not written by `bin/rails credentials:edit`: encrypted as
+/// // Rails encrypts credentials, under a fixed IV
+/// let master_key = "5f3c1d2e4a6b8c9d0e1f2a3b4c5d6e7f";
+/// let content = "dGbQTPdg0SLVHU4MfNXcP3Z5IQhz47IvSfYN8GSFycEl3/ZznuNMQb+hw3fwwoCHKwOYx2qOxvEdT9GkHainFQU8jtZxF2RUpuOdprJNI6GPqdkCLHQb1fyMywANBBgmahVvd46VoPTPEvKb2wZf7hJS+iAyaH/2Aii3gWZcyrxCRbfRDM4NkzZlOZHl+MaMBoMJhIsHuqXBFVgpPOg=--AAECAwQFBgcICQoL--XSxMPnDNQkFUE4CeX7ByCA==\n";
+/// let yaml = credentials::decrypt(master_key, content).unwrap();
+/// assert_eq!(
+///   credentials::secret_key_base(&yaml).as_deref(),
+///   Some("rails-cookies-from-credentials")
+/// );
+/// assert!(credentials::decrypt("00000000000000000000000000000000", content).is_err());
+/// ```
+pub fn decrypt(master_key: &str, content: &str) -> Result<String, String> {
+  let decrypted = decrypt_aes_gcm(&decode_key(master_key)?, content.trim())?;
+  match marshal::load(&decrypted)? {
+    Value::String(credentials) => Ok(credentials),
+    value => Err(format!("Credentials are not a string: {}", value)),
+  }
+}
+
+/// Encrypts `contents` as `ActiveSupport::EncryptedFile#write` does, for
+/// `config/credentials.yml.enc` or another encrypted file read with `master_key`.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::credentials;
+///
+/// let master_key = credentials::generate_key();
+/// let content = credentials::encrypt(&master_key, "secret_key_base: monster\n").unwrap();
+/// assert_eq!(credentials::decrypt(&master_key, &content).unwrap(), "secret_key_base: monster\n");
+/// ```
+pub fn encrypt(master_key: &str, contents: &str) -> Result<String, String> {
+  let dumped = marshal::dump(&Value::String(contents.to_string()));
+  encrypt_aes_gcm(&decode_key(master_key)?, &dumped)
+}
+
+/// A new random key, as `ActiveSupport::EncryptedFile.generate_key` writes
+/// to `config/master.key`.
+pub fn generate_key() -> String {
+  let mut key = [0; 16];
+  SystemRandom::new()
+    .fill(&mut key)
+    .expect("Could not generate a key");
+  key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_key(master_key: &str) -> Result<Vec<u8>, String> {
+  let key = decode_hex(master_key.trim())?;
+  if key.len() != 16 {
+    return Err(format!("Master key is {} bytes instead of 16", key.len()));
+  }
+  Ok(key)
+}
+
+/// The top-level `secret_key_base` of decrypted credentials.
+///
+/// Only plain and quoted scalars are read, as `rails credentials:edit` writes them.
+pub fn secret_key_base(credentials: &str) -> Option<String> {
+  let value = credentials
+    .lines()
+    .find_map(|line| line.strip_prefix("secret_key_base:"))?
+    .trim();
+  let value = ['"', '\'']
+    .iter()
+    .find_map(|quote| {
+      value
+        .strip_prefix(*quote)
+        .and_then(|value| value.strip_suffix(*quote))
+    })
+    .unwrap_or(value);
+  (!value.is_empty()).then(|| value.to_string())
+}
@@ -0,0 +1,539 @@
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use base64::prelude::*;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{aead, hmac, pbkdf2};
+use serde::{Deserialize, Serialize};
+
+/// The default salt of encrypted cookies, `authenticated_encrypted_cookie_salt`.
+pub const AUTHENTICATED_ENCRYPTED_COOKIE_SALT: &str = "authenticated encrypted cookie";
+/// The default salt of legacy encrypted cookies, `encrypted_cookie_salt`.
+pub const ENCRYPTED_COOKIE_SALT: &str = "encrypted cookie";
+/// The default salt of the HMAC key of legacy encrypted cookies, `encrypted_signed_cookie_salt`.
+pub const ENCRYPTED_SIGNED_COOKIE_SALT: &str = "signed encrypted cookie";
+/// The default salt of signed cookies, `signed_cookie_salt`.
+pub const SIGNED_COOKIE_SALT: &str = "signed cookie";
+
+/// The length of the keys Rails derives when none is given, e.g. for HMAC.
+const DEFAULT_KEY_LENGTH: usize = 64;
+
+/// The salts keys of signed and encrypted cookies are derived with, the
+/// `config.action_dispatch.*_cookie_salt` settings of an app.
+///
+/// * signed: `signed_cookie_salt`, of the HMAC key of signed cookies
+/// * encrypted: `encrypted_cookie_salt`, of the key of legacy encrypted cookies
+/// * encrypted_signed: `encrypted_signed_cookie_salt`, of the HMAC key of
+///   legacy encrypted cookies
+/// * authenticated_encrypted: `authenticated_encrypted_cookie_salt`, of the
+///   key of encrypted cookies
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::crypto::CookieSalts;
+///
+/// let salts = CookieSalts::default();
+/// assert_eq!(salts.authenticated_encrypted, "authenticated encrypted cookie");
+/// assert_eq!(salts.settings()[0], ("signed_cookie_salt", "signed cookie"));
+/// ```
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookieSalts {
+  pub signed: String,
+  pub encrypted: String,
+  pub encrypted_signed: String,
+  pub authenticated_encrypted: String,
+}
+
+impl Default for CookieSalts {
+  fn default() -> Self {
+    Self {
+      signed: SIGNED_COOKIE_SALT.to_string(),
+      encrypted: ENCRYPTED_COOKIE_SALT.to_string(),
+      encrypted_signed: ENCRYPTED_SIGNED_COOKIE_SALT.to_string(),
+      authenticated_encrypted: AUTHENTICATED_ENCRYPTED_COOKIE_SALT.to_string(),
+    }
+  }
+}
+
+impl CookieSalts {
+  /// The salts with the name of the setting they are configured with.
+  pub fn settings(&self) -> [(&'static str, &str); 4] {
+    [
+      ("signed_cookie_salt", &self.signed),
+      ("encrypted_cookie_salt", &self.encrypted),
+      ("encrypted_signed_cookie_salt", &self.encrypted_signed),
+      (
+        "authenticated_encrypted_cookie_salt",
+        &self.authenticated_encrypted,
+      ),
+    ]
+  }
+}
+
+/// How encrypted cookies are encrypted.
+///
+/// Rails 5.2+ encrypts cookies with `encrypted_cookie_cipher`, `aes-256-gcm`
+/// by default. Apps upgraded from older versions may set
+/// `use_authenticated_cookie_encryption = false`, and then encrypt them with
+/// `aes-256-cbc`, signed with HMAC-SHA1, with keys derived from other salts.
+/// A CBC `encrypted_cookie_cipher` is signed with its encryption key.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::crypto::CookieCipher;
+///
+/// let legacy: CookieCipher = "legacy-aes-256-cbc".parse().unwrap();
+/// assert!(!legacy.is_authenticated());
+/// assert_eq!(legacy.cipher(), "aes-256-cbc");
+/// assert_eq!(CookieCipher::Aes128Gcm.key_length(), 16);
+/// ```
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CookieCipher {
+  #[default]
+  Aes256Gcm,
+  Aes128Gcm,
+  Aes256Cbc,
+  LegacyAes256Cbc,
+}
+
+impl FromStr for CookieCipher {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    Self::ALL
+      .into_iter()
+      .find(|cipher| cipher.name() == name)
+      .ok_or_else(|| format!("Unknown cookie cipher: {}", name))
+  }
+}
+
+impl CookieCipher {
+  pub const ALL: [CookieCipher; 4] = [
+    CookieCipher::Aes256Gcm,
+    CookieCipher::Aes128Gcm,
+    CookieCipher::Aes256Cbc,
+    CookieCipher::LegacyAes256Cbc,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Aes256Gcm => "aes-256-gcm",
+      Self::Aes128Gcm => "aes-128-gcm",
+      Self::Aes256Cbc => "aes-256-cbc",
+      Self::LegacyAes256Cbc => "legacy-aes-256-cbc",
+    }
+  }
+
+  /// Whether `use_authenticated_cookie_encryption` is enabled.
+  pub fn is_authenticated(&self) -> bool {
+    *self != Self::LegacyAes256Cbc
+  }
+
+  /// The OpenSSL name of the cipher.
+  pub fn cipher(&self) -> &'static str {
+    match self {
+      Self::LegacyAes256Cbc => "aes-256-cbc",
+      _ => self.name(),
+    }
+  }
+
+  pub fn key_length(&self) -> usize {
+    match self {
+      Self::Aes128Gcm => 16,
+      _ => 32,
+    }
+  }
+
+  /// Decrypts an encrypted cookie of an app with `secret_key_base`, and keys
+  /// derived for its `salts`.
+  pub fn decrypt(
+    &self,
+    key_generator: KeyGenerator,
+    salts: &CookieSalts,
+    secret_key_base: &str,
+    cookie: &str,
+  ) -> Result<Vec<u8>, String> {
+    let derive = |salt: &str, length: usize| key_generator.derive(secret_key_base, salt, length);
+    match self {
+      Self::Aes256Gcm | Self::Aes128Gcm => decrypt_aes_gcm(
+        &derive(&salts.authenticated_encrypted, self.key_length()),
+        cookie,
+      ),
+      Self::Aes256Cbc => {
+        let key = derive(&salts.authenticated_encrypted, self.key_length());
+        decrypt_aes_cbc(&key, &key, cookie)
+      }
+      Self::LegacyAes256Cbc => decrypt_aes_cbc(
+        &derive(&salts.encrypted, self.key_length()),
+        &derive(&salts.encrypted_signed, DEFAULT_KEY_LENGTH),
+        cookie,
+      ),
+    }
+  }
+
+  /// Encrypts a cookie as an app with `secret_key_base` and `salts` would, see
+  /// [`CookieCipher::decrypt`].
+  pub fn encrypt(
+    &self,
+    key_generator: KeyGenerator,
+    salts: &CookieSalts,
+    secret_key_base: &str,
+    data: &[u8],
+  ) -> Result<String, String> {
+    let derive = |salt: &str, length: usize| key_generator.derive(secret_key_base, salt, length);
+    match self {
+      Self::Aes256Gcm | Self::Aes128Gcm => encrypt_aes_gcm(
+        &derive(&salts.authenticated_encrypted, self.key_length()),
+        data,
+      ),
+      Self::Aes256Cbc => {
+        let key = derive(&salts.authenticated_encrypted, self.key_length());
+        encrypt_aes_cbc(&key, &key, data)
+      }
+      Self::LegacyAes256Cbc => encrypt_aes_cbc(
+        &derive(&salts.encrypted, self.key_length()),
+        &derive(&salts.encrypted_signed, DEFAULT_KEY_LENGTH),
+        data,
+      ),
+    }
+  }
+}
+
+/// Signs a cookie as an app with `secret_key_base` and `salts` would, with
+/// `ActiveSupport::MessageVerifier` and its default `signed_cookie_digest`:
+/// `<Base64 data>--<hex HMAC-SHA1>`.
+pub fn sign_cookie(
+  key_generator: KeyGenerator,
+  salts: &CookieSalts,
+  secret_key_base: &str,
+  data: &[u8],
+) -> String {
+  let key = key_generator.derive(secret_key_base, &salts.signed, DEFAULT_KEY_LENGTH);
+  sign_hmac_sha1(&key, BASE64_STANDARD.encode(data))
+}
+
+/// Verifies a signed cookie of an app with `secret_key_base` and `salts`,
+/// see [`sign_cookie`], and returns its decoded data.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::crypto::{verify_cookie, CookieSalts, KeyGenerator};
+///
+/// // `cookies.signed[:signed] = "correct-horse-battery-staple"` in Rails 8.0, signed with Python's hmac
+/// let cookie = "eyJfcmFpbHMiOnsiZGF0YSI6ImNvcnJlY3QtaG9yc2UtYmF0dGVyeS1zdGFwbGUiLCJwdXIiOiJjb29raWUuc2lnbmVkIn19--90fe959d1194a153476ad596c88dfc8748d4ea10";
+/// let (key_generator, salts) = (KeyGenerator::for_version("8.0.1"), CookieSalts::default());
+/// let data = verify_cookie(key_generator, &salts, "rails-cookies-everywhere", cookie).unwrap();
+/// assert!(data.starts_with(br#"{"_rails":"#));
+///
+/// assert!(verify_cookie(key_generator, &salts, "another-secret", cookie).is_err());
+/// let tampered = cookie.replacen("eyJ", "eyK", 1);
+/// assert!(verify_cookie(key_generator, &salts, "rails-cookies-everywhere", &tampered).is_err());
+/// ```
+pub fn verify_cookie(
+  key_generator: KeyGenerator,
+  salts: &CookieSalts,
+  secret_key_base: &str,
+  cookie: &str,
+) -> Result<Vec<u8>, String> {
+  let Some((data, digest)) = cookie.rsplit_once("--") else {
+    return Err("Signed cookie without digest".to_string());
+  };
+  let key = key_generator.derive(secret_key_base, &salts.signed, DEFAULT_KEY_LENGTH);
+  verify_hmac_sha1(&key, data, digest)?;
+  BASE64_STANDARD
+    .decode(data)
+    .map_err(|err| format!("Invalid signed cookie encoding: {}", err))
+}
+
+/// Checks the hex HMAC-SHA1 `digest` of `signed` with `key`, in constant time.
+fn verify_hmac_sha1(key: &[u8], signed: &str, digest: &str) -> Result<(), String> {
+  let digest = decode_hex(digest)?;
+  let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+  hmac::verify(&key, signed.as_bytes(), &digest)
+    .map_err(|_| "Invalid message digest: wrong key or tampered message".to_string())
+}
+
+/// Appends the hex HMAC-SHA1 of `signed` with `key`, as `MessageVerifier` does.
+fn sign_hmac_sha1(key: &[u8], signed: String) -> String {
+  let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+  let digest: String = hmac::sign(&key, signed.as_bytes())
+    .as_ref()
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect();
+  format!("{}--{}", signed, digest)
+}
+
+/// The digest of the PBKDF2 key generator of Rails,
+/// `config.active_support.key_generator_hash_digest_class` from Rails 7.0.
+///
+/// Rails 5.2 and 6 use SHA1, 7.0 switched to SHA256.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyDigest {
+  Sha1,
+  Sha256,
+}
+
+impl FromStr for KeyDigest {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    match name {
+      "sha1" => Ok(Self::Sha1),
+      "sha256" => Ok(Self::Sha256),
+      _ => Err(format!("Unknown key generator digest: {}", name)),
+    }
+  }
+}
+
+impl KeyDigest {
+  /// The default digest of `rails_version`.
+  pub fn for_version(rails_version: &str) -> Self {
+    match rails_version.split('.').next() {
+      Some("5" | "6") => Self::Sha1,
+      _ => Self::Sha256,
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Sha1 => "sha1",
+      Self::Sha256 => "sha256",
+    }
+  }
+
+  /// The Ruby class of the digest.
+  pub fn ruby_class(&self) -> &'static str {
+    match self {
+      Self::Sha1 => "OpenSSL::Digest::SHA1",
+      Self::Sha256 => "OpenSSL::Digest::SHA256",
+    }
+  }
+}
+
+/// The PBKDF2 key generator deriving keys from the secret key base, as
+/// `ActiveSupport::KeyGenerator` does.
+///
+/// * digest: The digest of PBKDF2
+/// * iterations: The iterations of PBKDF2, 1000 for `Rails.application.key_generator`
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::crypto::{KeyDigest, KeyGenerator};
+///
+/// let key_generator = KeyGenerator::for_version("6.1.7");
+/// assert_eq!(key_generator, KeyGenerator::new(KeyDigest::Sha1, 1000));
+/// assert_eq!(key_generator.derive("secret", "salt", 32).len(), 32);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyGenerator {
+  pub digest: KeyDigest,
+  pub iterations: u32,
+}
+
+impl KeyGenerator {
+  /// The iterations of the key generator of Rails apps.
+  pub const ITERATIONS: u32 = 1000;
+
+  pub fn new(digest: KeyDigest, iterations: u32) -> Self {
+    Self { digest, iterations }
+  }
+
+  /// The key generator of a Rails app left to the defaults of its version.
+  pub fn for_version(rails_version: &str) -> Self {
+    Self::new(KeyDigest::for_version(rails_version), Self::ITERATIONS)
+  }
+
+  /// Derives a key of `length` bytes for `salt`.
+  pub fn derive(&self, secret_key_base: &str, salt: &str, length: usize) -> Vec<u8> {
+    let algorithm = match self.digest {
+      KeyDigest::Sha1 => pbkdf2::PBKDF2_HMAC_SHA1,
+      KeyDigest::Sha256 => pbkdf2::PBKDF2_HMAC_SHA256,
+    };
+    let iterations = NonZeroU32::new(self.iterations).unwrap_or(NonZeroU32::MIN);
+    let mut key = vec![0; length];
+    pbkdf2::derive(
+      algorithm,
+      iterations,
+      salt.as_bytes(),
+      secret_key_base.as_bytes(),
+      &mut key,
+    );
+    key
+  }
+}
+
+/// Decrypts an `aes-256-gcm` message, `<data>--<iv>--<auth tag>` in Base64.
+///
+/// The decrypted bytes are returned as is: they are only UTF-8 with the JSON
+/// serializer.
+pub fn decrypt_aes_gcm(key: &[u8], message: &str) -> Result<Vec<u8>, String> {
+  let parts = message
+    .split("--")
+    .map(|part| BASE64_STANDARD.decode(part))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|err| format!("Invalid encrypted message encoding: {}", err))?;
+  let [data, iv, auth_tag] = parts.as_slice() else {
+    return Err(format!(
+      "Encrypted message has {} parts instead of 3",
+      parts.len()
+    ));
+  };
+  let algorithm = match key.len() {
+    16 => &aead::AES_128_GCM,
+    _ => &aead::AES_256_GCM,
+  };
+  let key = aead::UnboundKey::new(algorithm, key).map_err(|_| "Invalid AES key".to_string())?;
+  let nonce =
+    aead::Nonce::try_assume_unique_for_key(iv).map_err(|_| "Invalid GCM IV".to_string())?;
+  let mut in_out = [
+    data.as_slice(),
+    auth_tag.as_slice(),
+  ]
+  .concat();
+  let decrypted = aead::LessSafeKey::new(key)
+    .open_in_place(nonce, aead::Aad::empty(), &mut in_out)
+    .map_err(|_| "Could not decrypt message: wrong key or tampered message".to_string())?;
+  Ok(decrypted.to_vec())
+}
+
+/// Encrypts a message as [`decrypt_aes_gcm`] decrypts it, with a random IV.
+pub fn encrypt_aes_gcm(key: &[u8], data: &[u8]) -> Result<String, String> {
+  let algorithm = match key.len() {
+    16 => &aead::AES_128_GCM,
+    _ => &aead::AES_256_GCM,
+  };
+  let key = aead::UnboundKey::new(algorithm, key).map_err(|_| "Invalid AES key".to_string())?;
+  let mut iv = [0; aead::NONCE_LEN];
+  SystemRandom::new()
+    .fill(&mut iv)
+    .map_err(|_| "Could not generate an IV".to_string())?;
+  let mut data = data.to_vec();
+  let auth_tag = aead::LessSafeKey::new(key)
+    .seal_in_place_separate_tag(
+      aead::Nonce::assume_unique_for_key(iv),
+      aead::Aad::empty(),
+      &mut data,
+    )
+    .map_err(|_| "Could not encrypt message".to_string())?;
+  Ok(
+    [
+      data.as_slice(),
+      &iv,
+      auth_tag.as_ref(),
+    ]
+    .map(|part| BASE64_STANDARD.encode(part))
+    .join("--"),
+  )
+}
+
+/// Verifies and decrypts an `aes-256-cbc` message.
+///
+/// The message is `<Base64 of "<data>--<iv>">--<hex HMAC-SHA1>`, with data
+/// and IV in Base64, and the HMAC computed with `sign_key` over the outer
+/// Base64.
+pub fn decrypt_aes_cbc(key: &[u8], sign_key: &[u8], message: &str) -> Result<Vec<u8>, String> {
+  let Some((signed, digest)) = message.rsplit_once("--") else {
+    return Err("Encrypted message without digest".to_string());
+  };
+  verify_hmac_sha1(sign_key, signed, digest)?;
+
+  let signed = BASE64_STANDARD
+    .decode(signed)
+    .map_err(|err| format!("Invalid encrypted message encoding: {}", err))?;
+  let signed = String::from_utf8(signed).map_err(|err| err.to_string())?;
+  let Some((data, iv)) = signed.split_once("--") else {
+    return Err("Encrypted message without IV".to_string());
+  };
+  let decode = |part: &str| {
+    BASE64_STANDARD
+      .decode(part)
+      .map_err(|err| format!("Invalid encrypted message encoding: {}", err))
+  };
+  let (mut data, iv) = (decode(data)?, decode(iv)?);
+  if iv.len() != 16 || data.is_empty() || !data.len().is_multiple_of(16) {
+    return Err("Invalid CBC message length".to_string());
+  }
+
+  let cipher = aes::Aes256::new_from_slice(key).map_err(|_| "Invalid AES key".to_string())?;
+  let mut previous: [u8; 16] = iv.try_into().unwrap();
+  for block in data.chunks_exact_mut(16) {
+    let encrypted: [u8; 16] = (&*block).try_into().unwrap();
+    cipher.decrypt_block(block.into());
+    block
+      .iter_mut()
+      .zip(previous)
+      .for_each(|(byte, mask)| *byte ^= mask);
+    previous = encrypted;
+  }
+
+  // PKCS#7 padding
+  let padding = *data.last().unwrap() as usize;
+  if padding == 0 || padding > 16 || !data.ends_with(&vec![padding as u8; padding]) {
+    return Err("Invalid CBC padding".to_string());
+  }
+  data.truncate(data.len() - padding);
+  Ok(data)
+}
+
+/// Encrypts a message as [`decrypt_aes_cbc`] decrypts it, with a random IV.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::crypto::{decrypt_aes_cbc, encrypt_aes_cbc};
+///
+/// let (key, sign_key) = ([1; 32], [2; 64]);
+/// let message = encrypt_aes_cbc(&key, &sign_key, b"sixteen bytes!!!").unwrap();
+/// assert_eq!(decrypt_aes_cbc(&key, &sign_key, &message).unwrap(), b"sixteen bytes!!!");
+/// assert!(decrypt_aes_cbc(&key, &[3; 64], &message).is_err());
+/// ```
+pub fn encrypt_aes_cbc(key: &[u8], sign_key: &[u8], data: &[u8]) -> Result<String, String> {
+  let cipher = aes::Aes256::new_from_slice(key).map_err(|_| "Invalid AES key".to_string())?;
+  let mut iv = [0; 16];
+  SystemRandom::new()
+    .fill(&mut iv)
+    .map_err(|_| "Could not generate an IV".to_string())?;
+
+  // PKCS#7 padding
+  let padding = 16 - data.len() % 16;
+  let mut data = [
+    data,
+    &vec![padding as u8; padding],
+  ]
+  .concat();
+  let mut previous = iv;
+  for block in data.chunks_exact_mut(16) {
+    block
+      .iter_mut()
+      .zip(previous)
+      .for_each(|(byte, mask)| *byte ^= mask);
+    cipher.encrypt_block(block.into());
+    previous = (&*block).try_into().unwrap();
+  }
+
+  let signed = format!(
+    "{}--{}",
+    BASE64_STANDARD.encode(&data),
+    BASE64_STANDARD.encode(iv)
+  );
+  Ok(sign_hmac_sha1(sign_key, BASE64_STANDARD.encode(signed)))
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+  if !hex.len().is_multiple_of(2) {
+    return Err(format!("Invalid hex string: {}", hex));
+  }
+  (0..hex.len())
+    .step_by(2)
+    .map(|index| {
+      hex
+        .get(index..index + 2)
+        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        .ok_or_else(|| format!("Invalid hex string: {}", hex))
+    })
+    .collect()
+}
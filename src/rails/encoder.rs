@@ -0,0 +1,208 @@
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{Map, Value};
+
+use super::crypto::{sign_cookie, CookieCipher, CookieSalts, KeyGenerator};
+use super::envelope::Envelope;
+use super::expected_purpose;
+use super::serializer::Serializer;
+
+/// Writes signed, encrypted and session cookies as a Rails app would, for
+/// the app to read them back.
+///
+/// * rails_version: The Rails version of the app, which decides the envelope
+///   and purpose of the messages
+/// * serializer: The cookies serializer of the app
+/// * cipher: How the app encrypts its cookies
+/// * key_generator: The key generator of the app
+/// * salts: The cookie salts of the app
+/// * secret_key_base: The secret of the app
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::crypto::CookieCipher;
+/// use rails_cookies_monster::rails::decipher_envelope;
+/// use rails_cookies_monster::rails::encoder::CookieEncoder;
+///
+/// let encoder = CookieEncoder::new("8.0.1", "rails-cookies-everywhere");
+/// let envelope = encoder.envelope("signed", "correct-horse-battery-staple".into(), None);
+/// // Captured from Rails 8.0.1, as in `cookies/v8.0.1`
+/// assert_eq!(
+///   encoder.signed(&envelope),
+///   "eyJfcmFpbHMiOnsibWVzc2FnZSI6IkltTnZjbkpsWTNRdGFHOXljMlV0WW1GMGRHVnllUzF6ZEdGd2JHVWkiLCJleHAiOm51bGwsInB1ciI6ImNvb2tpZS5zaWduZWQifX0=--cadcf13e6757b70bc9eff85f1ccdb1a2ad67f406"
+/// );
+///
+/// let legacy = CookieEncoder {
+///   cipher: CookieCipher::LegacyAes256Cbc,
+///   ..CookieEncoder::new("7.0.8", "rails-cookies-everywhere")
+/// };
+/// let envelope = legacy.envelope("legacy", "correct-horse-battery-staple".into(), None);
+/// let cookie = legacy.encrypted(&envelope).unwrap();
+/// let decrypted = decipher_envelope(
+///   legacy.serializer,
+///   legacy.cipher,
+///   legacy.key_generator,
+///   &legacy.salts,
+///   &legacy.secret_key_base,
+///   &cookie,
+/// );
+/// assert_eq!(decrypted.unwrap(), envelope);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CookieEncoder {
+  pub rails_version: String,
+  pub serializer: Serializer,
+  pub cipher: CookieCipher,
+  pub key_generator: KeyGenerator,
+  pub salts: CookieSalts,
+  pub secret_key_base: String,
+}
+
+impl CookieEncoder {
+  /// An encoder for an app with `secret_key_base`, left to the defaults of
+  /// `rails_version`.
+  pub fn new(rails_version: &str, secret_key_base: &str) -> Self {
+    Self {
+      rails_version: rails_version.to_string(),
+      serializer: Serializer::default(),
+      cipher: CookieCipher::default(),
+      key_generator: KeyGenerator::for_version(rails_version),
+      salts: CookieSalts::default(),
+      secret_key_base: secret_key_base.to_string(),
+    }
+  }
+
+  /// The envelope Rails writes `message` in for the cookie `cookie_name`,
+  /// with the purpose of that name from Rails 6.0, and `expires_at` if set.
+  pub fn envelope(
+    &self,
+    cookie_name: &str,
+    message: Value,
+    expires_at: Option<DateTime<Utc>>,
+  ) -> Envelope {
+    Envelope {
+      message,
+      expires_at,
+      purpose: expected_purpose(&self.rails_version, cookie_name),
+    }
+  }
+
+  /// The value of a signed cookie holding `envelope`, as
+  /// `cookies.signed[name] =` writes it.
+  pub fn signed(&self, envelope: &Envelope) -> String {
+    sign_cookie(
+      self.key_generator,
+      &self.salts,
+      &self.secret_key_base,
+      &envelope.dump(self.serializer),
+    )
+  }
+
+  /// The value of an encrypted cookie holding `envelope`, as
+  /// `cookies.encrypted[name] =` writes it with the cipher of the app.
+  pub fn encrypted(&self, envelope: &Envelope) -> Result<String, String> {
+    self.cipher.encrypt(
+      self.key_generator,
+      &self.salts,
+      &self.secret_key_base,
+      &envelope.dump(self.serializer),
+    )
+  }
+
+  /// The value of the session cookie named `session_key` holding `session`,
+  /// as the cookie store writes it: encrypted, under a new `session_id`
+  /// unless `session` has one.
+  pub fn session(&self, session_key: &str, session: &Map<String, Value>) -> Result<String, String> {
+    let mut session = session.clone();
+    if !session.contains_key("session_id") {
+      let mut session_id = [0; 16];
+      SystemRandom::new()
+        .fill(&mut session_id)
+        .map_err(|_| "Could not generate a session ID".to_string())?;
+      let session_id: String = session_id
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+      session.insert("session_id".to_string(), session_id.into());
+    }
+    self.encrypted(&self.envelope(session_key, Value::Object(session), None))
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use std::collections::HashMap;
+
+  use serde_json::json;
+  use urlencoding::decode;
+
+  use super::CookieEncoder;
+  use crate::rails::envelope::Envelope;
+
+  /// The cookies captured from Rails 8.0.1, URL-decoded, by name.
+  pub(crate) fn captured() -> HashMap<String, String> {
+    let fixture = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/cookies/v8.0.1"));
+    fixture
+      .lines()
+      .filter_map(|line| line.split_once('='))
+      .map(|(name, value)| (name.to_string(), decode(value).unwrap().into_owned()))
+      .collect()
+  }
+
+  fn encoder() -> CookieEncoder {
+    CookieEncoder::new("8.0.1", "rails-cookies-everywhere")
+  }
+
+  /// The decrypted contents of a cookie of the encoder's app.
+  fn decrypt(encoder: &CookieEncoder, cookie: &str) -> Vec<u8> {
+    let (key_generator, salts) = (encoder.key_generator, &encoder.salts);
+    let secret = &encoder.secret_key_base;
+    encoder
+      .cipher
+      .decrypt(key_generator, salts, secret, cookie)
+      .unwrap()
+  }
+
+  #[test]
+  fn signed_reproduces_rails() {
+    let encoder = encoder();
+    let envelope = encoder.envelope("signed", "correct-horse-battery-staple".into(), None);
+    assert_eq!(encoder.signed(&envelope), captured()["signed"]);
+  }
+
+  #[test]
+  fn encrypted_reproduces_rails() {
+    let encoder = encoder();
+    let envelope = encoder.envelope("encrypted", "correct-horse-battery-staple".into(), None);
+    let decrypted = decrypt(&encoder, &captured()["encrypted"]);
+    assert_eq!(decrypted, envelope.dump(encoder.serializer));
+    let encrypted = encoder.encrypted(&envelope).unwrap();
+    assert_eq!(decrypt(&encoder, &encrypted), decrypted);
+  }
+
+  #[test]
+  fn session_reproduces_rails() {
+    let (encoder, key) = (encoder(), "_cookie_monster_session");
+    let decrypted = decrypt(&encoder, &captured()[key]);
+    let captured = Envelope::parse(&decrypted, encoder.serializer).unwrap();
+    assert_eq!(captured.message["session"], "correct-horse-battery-staple");
+
+    // Rails keeps the order the entries were set in, the encoder sorts them.
+    let session = captured.message.as_object().unwrap();
+    let cookie = encoder.session(key, session).unwrap();
+    let written = Envelope::parse(&decrypt(&encoder, &cookie), encoder.serializer).unwrap();
+    assert_eq!(written, captured);
+    let mut session = session.clone();
+    session.remove("session_id");
+    let cookie = encoder.session(key, &session).unwrap();
+    let written = Envelope::parse(&decrypt(&encoder, &cookie), encoder.serializer).unwrap();
+    assert_eq!(
+      written.message["session"],
+      json!("correct-horse-battery-staple")
+    );
+    assert_ne!(
+      written.message["session_id"],
+      captured.message["session_id"]
+    );
+  }
+}
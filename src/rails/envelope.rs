@@ -0,0 +1,158 @@
+use base64::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+
+use super::serializer::Serializer;
+
+/// A Rails message, with the metadata Rails wraps it in.
+///
+/// Signed and encrypted cookies hold `{"_rails":{...}}` once decoded: a JSON
+/// envelope with the message serialized then Base64-encoded under `message`.
+/// Messages written whole by their serializer from Rails 7.1 hold the message
+/// as is under `data` instead. Messages without metadata are not wrapped at all.
+///
+/// * message: The deserialized message
+/// * expires_at: When the message expires, if it does
+/// * purpose: What the message was created for, e.g. `cookie.<name>`
+///
+/// # Examples
+/// ```
+/// use chrono::{TimeZone, Utc};
+/// use rails_cookies_monster::rails::envelope::Envelope;
+/// use rails_cookies_monster::rails::serializer::Serializer;
+///
+/// let decoded = r#"{"_rails":{"message":"ImZvbyI=","exp":"2025-01-01T00:00:00.000Z","pur":"cookie.foo"}}"#;
+/// let envelope = Envelope::parse(decoded.as_bytes(), Serializer::Json).unwrap();
+/// assert_eq!(envelope.message, "foo");
+/// assert_eq!(envelope.purpose.as_deref(), Some("cookie.foo"));
+/// assert!(envelope.verify_purpose(Some("cookie.foo")).is_ok());
+/// assert!(envelope.verify_purpose(Some("cookie.bar")).is_err());
+///
+/// let before = Utc.with_ymd_and_hms(2024, 12, 31, 23, 59, 59).unwrap();
+/// let after = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+/// assert!(envelope.verify_expiry(before).is_ok());
+/// assert!(envelope.verify_expiry(after).is_err());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+  pub message: Value,
+  pub expires_at: Option<DateTime<Utc>>,
+  pub purpose: Option<String>,
+}
+
+impl Envelope {
+  /// Unwraps a decoded message written with `serializer`.
+  ///
+  /// The envelope itself may be JSON whatever the serializer, as before Rails 7.1.
+  pub fn parse(decoded: &[u8], serializer: Serializer) -> Result<Self, String> {
+    let outer = match Serializer::detect(decoded) {
+      Serializer::Json if serializer != Serializer::Json => Serializer::Json
+        .load(decoded)
+        .or_else(|_| serializer.load(decoded))?,
+      _ => serializer.load(decoded)?,
+    };
+    let Some(metadata) = outer.get("_rails").and_then(Value::as_object) else {
+      return Ok(Self {
+        message: outer,
+        expires_at: None,
+        purpose: None,
+      });
+    };
+
+    let message = match (metadata.get("message"), metadata.get("data")) {
+      (Some(Value::String(message)), _) => serializer.load(
+        &BASE64_STANDARD
+          .decode(message)
+          .map_err(|err| format!("Invalid message encoding: {}", err))?,
+      )?,
+      (_, Some(data)) => data.clone(),
+      _ => return Err("Envelope without message".to_string()),
+    };
+    let expires_at = match metadata.get("exp") {
+      Some(Value::String(exp)) => Some(
+        DateTime::parse_from_rfc3339(exp)
+          .map_err(|err| format!("Invalid expiry {}: {}", exp, err))?
+          .with_timezone(&Utc),
+      ),
+      _ => None,
+    };
+    let purpose = metadata
+      .get("pur")
+      .and_then(Value::as_str)
+      .map(str::to_owned);
+    Ok(Self {
+      message,
+      expires_at,
+      purpose,
+    })
+  }
+
+  /// Serializes the message and its metadata as Rails writes them in a
+  /// cookie, for [`Envelope::parse`] and Rails to read back.
+  ///
+  /// Cookies keep the JSON envelope around the serialized message after
+  /// Rails 7.1, as captured from Rails 8.0.1 in `cookies/v8.0.1`: its keys in
+  /// Rails' order, with a `null` expiry or purpose.
+  ///
+  /// # Examples
+  /// ```
+  /// use rails_cookies_monster::rails::envelope::Envelope;
+  /// use rails_cookies_monster::rails::serializer::Serializer;
+  ///
+  /// let envelope = Envelope {
+  ///   message: "foo".into(),
+  ///   expires_at: None,
+  ///   purpose: Some("cookie.foo".to_string()),
+  /// };
+  /// let dumped = envelope.dump(Serializer::Marshal);
+  /// assert!(dumped.starts_with(br#"{"_rails":{"message":"BAhJIghmb28GOgZFVA==""#));
+  /// assert_eq!(Envelope::parse(&dumped, Serializer::Marshal).unwrap(), envelope);
+  /// let dumped = envelope.dump(Serializer::Json);
+  /// assert_eq!(dumped, br#"{"_rails":{"message":"ImZvbyI=","exp":null,"pur":"cookie.foo"}}"#);
+  /// ```
+  pub fn dump(&self, serializer: Serializer) -> Vec<u8> {
+    if self.expires_at.is_none() && self.purpose.is_none() {
+      return serializer.dump(&self.message);
+    }
+    let message = BASE64_STANDARD.encode(serializer.dump(&self.message));
+    let exp = self
+      .expires_at
+      .map(|expires_at| expires_at.to_rfc3339_opts(SecondsFormat::Millis, true));
+    // Written by hand, as JSON objects do not keep the order of their keys.
+    format!(
+      r#"{{"_rails":{{"message":{},"exp":{},"pur":{}}}}}"#,
+      json!(message),
+      json!(exp),
+      json!(self.purpose)
+    )
+    .into_bytes()
+  }
+
+  pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+    self.expires_at.is_some_and(|expires_at| expires_at <= now)
+  }
+
+  /// Rejects the message if it expired at `now`, as Rails does.
+  pub fn verify_expiry(&self, now: DateTime<Utc>) -> Result<(), String> {
+    match self.expires_at {
+      Some(expires_at) if self.is_expired(now) => {
+        Err(format!("Message expired at {}", expires_at.to_rfc3339()))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Rejects the message if it was not created for `purpose`, as Rails does.
+  ///
+  /// Without an expected purpose, any message is accepted.
+  pub fn verify_purpose(&self, purpose: Option<&str>) -> Result<(), String> {
+    match purpose {
+      Some(purpose) if self.purpose.as_deref() != Some(purpose) => Err(format!(
+        "Purpose mismatch: expected {}, found {}",
+        purpose,
+        self.purpose.as_deref().unwrap_or("none")
+      )),
+      _ => Ok(()),
+    }
+  }
+}
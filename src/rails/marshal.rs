@@ -0,0 +1,478 @@
+use serde_json::{Map, Number, Value};
+
+/// The Marshal format version, written first in every dump.
+const VERSION: [u8; 2] = [4, 8];
+
+/// Loads a Ruby `Marshal.dump` as JSON.
+///
+/// Symbols become strings, hash keys are stringified as the JSON serializer
+/// would, and objects become a hash of their instance variables with their
+/// class under `"^o"`.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::marshal;
+///
+/// // Marshal.dump({ "a" => [1, nil, true] })
+/// let dumped = b"\x04\x08{\x06I\"\x06a\x06:\x06ET[\x08i\x060T";
+/// assert_eq!(marshal::load(dumped).unwrap(), serde_json::json!({ "a": [1, null, true] }));
+/// ```
+pub fn load(dumped: &[u8]) -> Result<Value, String> {
+  if !dumped.starts_with(&VERSION) {
+    return Err("Not a Marshal dump".to_string());
+  }
+  let mut loader = Loader {
+    bytes: dumped,
+    position: VERSION.len(),
+    symbols: vec![],
+    objects: vec![],
+  };
+  loader.value()
+}
+
+/// Whether `bytes` looks like a Marshal dump.
+pub fn is_marshal(bytes: &[u8]) -> bool {
+  bytes.starts_with(&VERSION)
+}
+
+struct Loader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+  symbols: Vec<String>,
+  objects: Vec<Value>,
+}
+
+impl Loader<'_> {
+  fn byte(&mut self) -> Result<u8, String> {
+    let byte = *self
+      .bytes
+      .get(self.position)
+      .ok_or("Truncated Marshal dump")?;
+    self.position += 1;
+    Ok(byte)
+  }
+
+  fn take(&mut self, length: usize) -> Result<&[u8], String> {
+    let end = self.position.saturating_add(length);
+    let bytes = self
+      .bytes
+      .get(self.position..end)
+      .ok_or("Truncated Marshal dump")?;
+    self.position = end;
+    Ok(bytes)
+  }
+
+  /// A packed integer: small values in one byte, others in up to four bytes.
+  fn fixnum(&mut self) -> Result<i64, String> {
+    let first = self.byte()? as i8;
+    match first {
+      0 => Ok(0),
+      5..=127 => Ok(first as i64 - 5),
+      -128..=-5 => Ok(first as i64 + 5),
+      1..=4 => {
+        let mut value = 0i64;
+        for index in 0..first {
+          value |= (self.byte()? as i64) << (8 * index);
+        }
+        Ok(value)
+      }
+      -4..=-1 => {
+        let mut value = -1i64;
+        for index in 0..-first {
+          value &= !(0xff << (8 * index));
+          value |= (self.byte()? as i64) << (8 * index);
+        }
+        Ok(value)
+      }
+    }
+  }
+
+  fn length(&mut self) -> Result<usize, String> {
+    usize::try_from(self.fixnum()?).map_err(|_| "Negative length in Marshal dump".to_string())
+  }
+
+  fn bytes(&mut self) -> Result<Vec<u8>, String> {
+    let length = self.length()?;
+    Ok(self.take(length)?.to_vec())
+  }
+
+  fn symbol(&mut self) -> Result<String, String> {
+    match self.byte()? {
+      b':' => {
+        let symbol = String::from_utf8_lossy(&self.bytes()?).to_string();
+        self.symbols.push(symbol.clone());
+        Ok(symbol)
+      }
+      b';' => {
+        let index = self.length()?;
+        self
+          .symbols
+          .get(index)
+          .cloned()
+          .ok_or_else(|| format!("Unknown symbol link {}", index))
+      }
+      b'I' => {
+        // Symbols with an encoding carry it as an instance variable.
+        let symbol = self.symbol()?;
+        self.ivars()?;
+        Ok(symbol)
+      }
+      other => Err(format!("Expected a symbol, found {:?}", other as char)),
+    }
+  }
+
+  fn ivars(&mut self) -> Result<Map<String, Value>, String> {
+    let count = self.length()?;
+    let mut ivars = Map::new();
+    for _ in 0..count {
+      let name = self.symbol()?;
+      let value = self.value()?;
+      ivars.insert(name, value);
+    }
+    Ok(ivars)
+  }
+
+  /// Registers an object for later links, returning its index.
+  fn register(&mut self, value: Value) -> usize {
+    self.objects.push(value);
+    self.objects.len() - 1
+  }
+
+  fn value(&mut self) -> Result<Value, String> {
+    let value = match self.byte()? {
+      b'0' => Value::Null,
+      b'T' => Value::Bool(true),
+      b'F' => Value::Bool(false),
+      b'i' => Value::from(self.fixnum()?),
+      b':' | b';' => {
+        self.position -= 1;
+        Value::String(self.symbol()?)
+      }
+      b'@' => {
+        let index = self.length()?;
+        return self
+          .objects
+          .get(index)
+          .cloned()
+          .ok_or_else(|| format!("Unknown object link {}", index));
+      }
+      b'I' => {
+        // Instance variables, e.g. the encoding of a string, follow the value.
+        let value = self.value()?;
+        self.ivars()?;
+        value
+      }
+      b'"' => {
+        let string = String::from_utf8_lossy(&self.bytes()?).to_string();
+        self.register(Value::String(string.clone()));
+        Value::String(string)
+      }
+      b'f' => {
+        let float = String::from_utf8_lossy(&self.bytes()?).to_string();
+        let value = float
+          .parse::<f64>()
+          .ok()
+          .and_then(Number::from_f64)
+          .map_or(Value::String(float), Value::Number);
+        self.register(value.clone());
+        value
+      }
+      b'l' => {
+        let value = self.bignum()?;
+        self.register(value.clone());
+        value
+      }
+      b'[' => {
+        let index = self.register(Value::Null);
+        let length = self.length()?;
+        // Each element takes a byte at least, a larger length is truncated.
+        let mut array = Vec::with_capacity(length.min(self.bytes.len() - self.position));
+        for _ in 0..length {
+          array.push(self.value()?);
+        }
+        self.objects[index] = Value::Array(array.clone());
+        Value::Array(array)
+      }
+      kind @ (b'{' | b'}') => {
+        let index = self.register(Value::Null);
+        let map = self.hash()?;
+        if kind == b'}' {
+          // The default value is irrelevant to the content.
+          self.value()?;
+        }
+        self.objects[index] = Value::Object(map.clone());
+        Value::Object(map)
+      }
+      b'C' => {
+        // A subclass of a core type, e.g. HashWithIndifferentAccess.
+        self.symbol()?;
+        self.value()?
+      }
+      b'e' => {
+        // An object extended with a module.
+        self.symbol()?;
+        self.value()?
+      }
+      b'o' => {
+        let index = self.register(Value::Null);
+        let class = self.symbol()?;
+        let mut object = self.ivars()?;
+        object.insert("^o".to_string(), Value::String(class));
+        self.objects[index] = Value::Object(object.clone());
+        Value::Object(object)
+      }
+      b'S' => {
+        let index = self.register(Value::Null);
+        let class = self.symbol()?;
+        let mut object = Map::new();
+        for _ in 0..self.length()? {
+          let member = self.symbol()?;
+          let value = self.value()?;
+          object.insert(member, value);
+        }
+        object.insert("^S".to_string(), Value::String(class));
+        self.objects[index] = Value::Object(object.clone());
+        Value::Object(object)
+      }
+      b'u' => {
+        // Objects dumped with `_dump`, e.g. Time: their data is opaque.
+        let class = self.symbol()?;
+        let data = self.bytes()?;
+        let value = serde_json::json!({ "^u": class, "data": String::from_utf8_lossy(&data) });
+        self.register(value.clone());
+        value
+      }
+      b'U' => {
+        let index = self.register(Value::Null);
+        let class = self.symbol()?;
+        let data = self.value()?;
+        let value = serde_json::json!({ "^U": class, "data": data });
+        self.objects[index] = value.clone();
+        value
+      }
+      b'/' => {
+        let source = String::from_utf8_lossy(&self.bytes()?).to_string();
+        self.byte()?;
+        self.register(Value::String(source.clone()));
+        Value::String(source)
+      }
+      kind @ (b'c' | b'm' | b'M') => {
+        let name = String::from_utf8_lossy(&self.bytes()?).to_string();
+        let value = serde_json::json!({ format!("^{}", kind as char): name });
+        self.register(value.clone());
+        value
+      }
+      other => return Err(format!("Unsupported Marshal type {:?}", other as char)),
+    };
+    Ok(value)
+  }
+
+  fn hash(&mut self) -> Result<Map<String, Value>, String> {
+    let length = self.length()?;
+    let mut map = Map::new();
+    for _ in 0..length {
+      let key = match self.value()? {
+        Value::String(key) => key,
+        key => key.to_string(),
+      };
+      let value = self.value()?;
+      map.insert(key, value);
+    }
+    Ok(map)
+  }
+
+  fn bignum(&mut self) -> Result<Value, String> {
+    let sign = self.byte()?;
+    // The length is in 16 bits words.
+    let length = self.length()? * 2;
+    let bytes = self.take(length)?;
+    if bytes.iter().skip(16).any(|byte| *byte != 0) {
+      return Err("Bignum too large".to_string());
+    }
+    let magnitude = bytes
+      .iter()
+      .take(16)
+      .enumerate()
+      .fold(0u128, |value, (index, byte)| {
+        value | (*byte as u128) << (8 * index)
+      });
+    let number = match (sign, u64::try_from(magnitude), i64::try_from(magnitude)) {
+      (b'+', Ok(value), _) => Value::from(value),
+      (b'-', _, Ok(value)) => Value::from(-value),
+      // Out of the JSON range, kept exact as a string.
+      (b'-', _, _) => Value::String(format!("-{}", magnitude)),
+      _ => Value::String(magnitude.to_string()),
+    };
+    Ok(number)
+  }
+}
+
+/// Dumps JSON as Ruby's `Marshal.dump` would dump the equivalent Ruby value.
+///
+/// Strings are UTF-8, hash keys strings, and integers out of the range of
+/// `Fixnum` are dumped as `Bignum`.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::marshal;
+///
+/// let value = serde_json::json!({ "a": [1, null, true], "b": "a" });
+/// let dumped = b"\x04\x08{\x07I\"\x06a\x06:\x06ET[\x08i\x060TI\"\x06b\x06;\x00TI\"\x06a\x06;\x00T";
+/// assert_eq!(marshal::dump(&value), dumped);
+/// assert_eq!(marshal::load(&marshal::dump(&value)).unwrap(), value);
+/// ```
+pub fn dump(value: &Value) -> Vec<u8> {
+  let mut dumper = Dumper {
+    bytes: VERSION.to_vec(),
+    symbols: vec![],
+  };
+  dumper.value(value);
+  dumper.bytes
+}
+
+struct Dumper {
+  bytes: Vec<u8>,
+  symbols: Vec<String>,
+}
+
+impl Dumper {
+  /// A packed integer, see [`Loader::fixnum`].
+  fn fixnum(&mut self, value: i64) {
+    match value {
+      0 => self.bytes.push(0),
+      1..=122 => self.bytes.push(value as u8 + 5),
+      -123..=-1 => self.bytes.push((value - 5) as u8),
+      _ => {
+        let mut packed = vec![];
+        let mut rest = value;
+        loop {
+          packed.push(rest as u8);
+          rest >>= 8;
+          if rest == 0 || rest == -1 {
+            break;
+          }
+        }
+        let length = packed.len() as i8;
+        self
+          .bytes
+          .push(if value < 0 { -length } else { length } as u8);
+        self.bytes.extend(packed);
+      }
+    }
+  }
+
+  fn bytes(&mut self, bytes: &[u8]) {
+    self.fixnum(bytes.len() as i64);
+    self.bytes.extend_from_slice(bytes);
+  }
+
+  fn symbol(&mut self, symbol: &str) {
+    match self.symbols.iter().position(|known| known == symbol) {
+      Some(index) => {
+        self.bytes.push(b';');
+        self.fixnum(index as i64);
+      }
+      None => {
+        self.bytes.push(b':');
+        self.bytes(symbol.as_bytes());
+        self.symbols.push(symbol.to_string());
+      }
+    }
+  }
+
+  /// A UTF-8 string, with its encoding as an instance variable.
+  fn string(&mut self, string: &str) {
+    self.bytes.extend_from_slice(b"I\"");
+    self.bytes(string.as_bytes());
+    self.fixnum(1);
+    self.symbol("E");
+    self.bytes.push(b'T');
+  }
+
+  fn integer(&mut self, value: i128) {
+    // Fixnums are 31-bit in Marshal dumps, whatever the platform.
+    if (-(1 << 30)..1 << 30).contains(&value) {
+      self.bytes.push(b'i');
+      self.fixnum(value as i64);
+      return;
+    }
+    self.bytes.push(b'l');
+    self.bytes.push(if value < 0 { b'-' } else { b'+' });
+    let mut magnitude: Vec<u8> = value
+      .unsigned_abs()
+      .to_le_bytes()
+      .into_iter()
+      .rev()
+      .skip_while(|byte| *byte == 0)
+      .collect();
+    magnitude.reverse();
+    if magnitude.len() % 2 == 1 {
+      magnitude.push(0);
+    }
+    self.fixnum(magnitude.len() as i64 / 2);
+    self.bytes.extend(magnitude);
+  }
+
+  fn float(&mut self, value: f64) {
+    let repr = match value {
+      value if value.is_nan() => "nan".to_string(),
+      value if value.is_infinite() && value > 0.0 => "inf".to_string(),
+      value if value.is_infinite() => "-inf".to_string(),
+      value => value.to_string(),
+    };
+    self.bytes.push(b'f');
+    self.bytes(repr.as_bytes());
+  }
+
+  fn value(&mut self, value: &Value) {
+    match value {
+      Value::Null => self.bytes.push(b'0'),
+      Value::Bool(true) => self.bytes.push(b'T'),
+      Value::Bool(false) => self.bytes.push(b'F'),
+      Value::Number(number) => match (number.as_i64(), number.as_u64(), number.as_f64()) {
+        (Some(value), _, _) => self.integer(value as i128),
+        (_, Some(value), _) => self.integer(value as i128),
+        (_, _, Some(value)) => self.float(value),
+        _ => self.bytes.push(b'0'),
+      },
+      Value::String(string) => self.string(string),
+      Value::Array(values) => {
+        self.bytes.push(b'[');
+        self.fixnum(values.len() as i64);
+        values.iter().for_each(|value| self.value(value));
+      }
+      Value::Object(map) => {
+        self.bytes.push(b'{');
+        self.fixnum(map.len() as i64);
+        for (key, value) in map {
+          self.string(key);
+          self.value(value);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::load;
+
+  #[test]
+  fn load_rejects_oversized_lengths() {
+    // An array, a hash and a string of 2^32 - 1 entries or bytes, truncated.
+    for kind in [b'[', b'{', b'"'] {
+      let dumped = [
+        4,
+        8,
+        kind,
+        4,
+        0xff,
+        0xff,
+        0xff,
+        0xff,
+        b'0',
+      ];
+      assert_eq!(load(&dumped).unwrap_err(), "Truncated Marshal dump");
+    }
+  }
+}
@@ -0,0 +1,325 @@
+use serde_json::{Map, Number, Value};
+
+/// The bytes `ActiveSupport::MessagePack` prefixes its dumps with.
+pub const SIGNATURE: [u8; 2] = [0xcc, 0x80];
+
+/// The extension type `ActiveSupport::MessagePack` packs symbols as.
+const SYMBOL_EXTENSION: i8 = 0;
+
+/// Loads a MessagePack dump of the `:message_pack` serializer (Rails 7.1+) as JSON.
+///
+/// The Rails signature is optional. Symbols become strings, and other Ruby
+/// types packed as extensions are rejected.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::message_pack;
+///
+/// // ActiveSupport::MessagePack.dump({ "a" => [1, nil, true] })
+/// let dumped = b"\xcc\x80\x81\xa1a\x93\x01\xc0\xc3";
+/// assert_eq!(message_pack::load(dumped).unwrap(), serde_json::json!({ "a": [1, null, true] }));
+/// ```
+pub fn load(dumped: &[u8]) -> Result<Value, String> {
+  let bytes = dumped.strip_prefix(&SIGNATURE).unwrap_or(dumped);
+  let mut loader = Loader { bytes, position: 0 };
+  let value = loader.value()?;
+  if loader.position != bytes.len() {
+    return Err("Trailing bytes after MessagePack value".to_string());
+  }
+  Ok(value)
+}
+
+/// Whether `bytes` starts with the `ActiveSupport::MessagePack` signature.
+pub fn is_message_pack(bytes: &[u8]) -> bool {
+  bytes.starts_with(&SIGNATURE)
+}
+
+struct Loader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl Loader<'_> {
+  fn take(&mut self, length: usize) -> Result<&[u8], String> {
+    let end = self.position + length;
+    let bytes = self
+      .bytes
+      .get(self.position..end)
+      .ok_or("Truncated MessagePack dump")?;
+    self.position = end;
+    Ok(bytes)
+  }
+
+  fn byte(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  /// A big-endian unsigned integer of `size` bytes.
+  fn unsigned(&mut self, size: usize) -> Result<u64, String> {
+    Ok(
+      self
+        .take(size)?
+        .iter()
+        .fold(0u64, |value, byte| value << 8 | *byte as u64),
+    )
+  }
+
+  /// A big-endian signed integer of `size` bytes.
+  fn signed(&mut self, size: usize) -> Result<i64, String> {
+    let shift = 64 - 8 * size as u32;
+    Ok(((self.unsigned(size)? << shift) as i64) >> shift)
+  }
+
+  fn string(&mut self, length: usize) -> Result<Value, String> {
+    Ok(Value::String(
+      String::from_utf8_lossy(self.take(length)?).to_string(),
+    ))
+  }
+
+  fn array(&mut self, length: usize) -> Result<Value, String> {
+    (0..length)
+      .map(|_| self.value())
+      .collect::<Result<Vec<_>, _>>()
+      .map(Value::Array)
+  }
+
+  fn map(&mut self, length: usize) -> Result<Value, String> {
+    let mut map = Map::new();
+    for _ in 0..length {
+      let key = match self.value()? {
+        Value::String(key) => key,
+        key => key.to_string(),
+      };
+      let value = self.value()?;
+      map.insert(key, value);
+    }
+    Ok(Value::Object(map))
+  }
+
+  fn extension(&mut self, length: usize) -> Result<Value, String> {
+    let kind = self.byte()? as i8;
+    let data = self.take(length)?;
+    match kind {
+      SYMBOL_EXTENSION => Ok(Value::String(String::from_utf8_lossy(data).to_string())),
+      kind => Err(format!("Unsupported MessagePack extension type {}", kind)),
+    }
+  }
+
+  fn float(value: f64) -> Value {
+    Number::from_f64(value).map_or_else(|| Value::String(value.to_string()), Value::Number)
+  }
+
+  fn value(&mut self) -> Result<Value, String> {
+    let marker = self.byte()?;
+    match marker {
+      0x00..=0x7f => Ok(Value::from(marker)),
+      0x80..=0x8f => self.map((marker & 0x0f) as usize),
+      0x90..=0x9f => self.array((marker & 0x0f) as usize),
+      0xa0..=0xbf => self.string((marker & 0x1f) as usize),
+      0xc0 => Ok(Value::Null),
+      0xc2 => Ok(Value::Bool(false)),
+      0xc3 => Ok(Value::Bool(true)),
+      0xc4 => {
+        let length = self.unsigned(1)? as usize;
+        self.string(length)
+      }
+      0xc5 => {
+        let length = self.unsigned(2)? as usize;
+        self.string(length)
+      }
+      0xc6 => {
+        let length = self.unsigned(4)? as usize;
+        self.string(length)
+      }
+      0xc7 => {
+        let length = self.unsigned(1)? as usize;
+        self.extension(length)
+      }
+      0xc8 => {
+        let length = self.unsigned(2)? as usize;
+        self.extension(length)
+      }
+      0xc9 => {
+        let length = self.unsigned(4)? as usize;
+        self.extension(length)
+      }
+      0xca => {
+        let bits = self.unsigned(4)? as u32;
+        Ok(Self::float(f32::from_bits(bits) as f64))
+      }
+      0xcb => {
+        let bits = self.unsigned(8)?;
+        Ok(Self::float(f64::from_bits(bits)))
+      }
+      0xcc => Ok(Value::from(self.unsigned(1)?)),
+      0xcd => Ok(Value::from(self.unsigned(2)?)),
+      0xce => Ok(Value::from(self.unsigned(4)?)),
+      0xcf => Ok(Value::from(self.unsigned(8)?)),
+      0xd0 => Ok(Value::from(self.signed(1)?)),
+      0xd1 => Ok(Value::from(self.signed(2)?)),
+      0xd2 => Ok(Value::from(self.signed(4)?)),
+      0xd3 => Ok(Value::from(self.signed(8)?)),
+      0xd4 => self.extension(1),
+      0xd5 => self.extension(2),
+      0xd6 => self.extension(4),
+      0xd7 => self.extension(8),
+      0xd8 => self.extension(16),
+      0xd9 => {
+        let length = self.unsigned(1)? as usize;
+        self.string(length)
+      }
+      0xda => {
+        let length = self.unsigned(2)? as usize;
+        self.string(length)
+      }
+      0xdb => {
+        let length = self.unsigned(4)? as usize;
+        self.string(length)
+      }
+      0xdc => {
+        let length = self.unsigned(2)? as usize;
+        self.array(length)
+      }
+      0xdd => {
+        let length = self.unsigned(4)? as usize;
+        self.array(length)
+      }
+      0xde => {
+        let length = self.unsigned(2)? as usize;
+        self.map(length)
+      }
+      0xdf => {
+        let length = self.unsigned(4)? as usize;
+        self.map(length)
+      }
+      0xe0..=0xff => Ok(Value::from(marker as i8)),
+      0xc1 => Err("Invalid MessagePack marker 0xc1".to_string()),
+    }
+  }
+}
+
+/// Dumps JSON as `ActiveSupport::MessagePack.dump` would dump the equivalent
+/// Ruby value, with the Rails signature.
+///
+/// Integers and lengths take the smallest format, floats are always doubles
+/// as in `msgpack-ruby`, and hash keys are strings.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::message_pack;
+///
+/// let value = serde_json::json!({ "a": [1, null, true] });
+/// assert_eq!(message_pack::dump(&value), b"\xcc\x80\x81\xa1a\x93\x01\xc0\xc3");
+/// let value = serde_json::json!([-33, 300, 1.5, u64::MAX, i64::MIN, "é"]);
+/// assert_eq!(message_pack::load(&message_pack::dump(&value)).unwrap(), value);
+/// ```
+pub fn dump(value: &Value) -> Vec<u8> {
+  let mut dumper = Dumper {
+    bytes: SIGNATURE.to_vec(),
+  };
+  dumper.value(value);
+  dumper.bytes
+}
+
+struct Dumper {
+  bytes: Vec<u8>,
+}
+
+impl Dumper {
+  /// The marker of an array or map of `length`: its fixed format up to 15
+  /// entries, then `marker16` or the 32-bit marker that follows it.
+  fn header(&mut self, length: usize, fixed: u8, marker16: u8) {
+    match length {
+      0..=15 => self.bytes.push(fixed | length as u8),
+      16..=0xffff => {
+        self.bytes.push(marker16);
+        self.bytes.extend((length as u16).to_be_bytes());
+      }
+      _ => {
+        self.bytes.push(marker16 + 1);
+        self.bytes.extend((length as u32).to_be_bytes());
+      }
+    }
+  }
+
+  fn string(&mut self, string: &str) {
+    let length = string.len();
+    match length {
+      0..=31 => self.bytes.push(0xa0 | length as u8),
+      32..=0xff => self.bytes.extend([0xd9, length as u8]),
+      0x100..=0xffff => {
+        self.bytes.push(0xda);
+        self.bytes.extend((length as u16).to_be_bytes());
+      }
+      _ => {
+        self.bytes.push(0xdb);
+        self.bytes.extend((length as u32).to_be_bytes());
+      }
+    }
+    self.bytes.extend_from_slice(string.as_bytes());
+  }
+
+  fn integer(&mut self, value: i128) {
+    match value {
+      -32..=0x7f => self.bytes.push(value as i8 as u8),
+      0x80..=0xff => self.bytes.extend([0xcc, value as u8]),
+      0x100..=0xffff => {
+        self.bytes.push(0xcd);
+        self.bytes.extend((value as u16).to_be_bytes());
+      }
+      0x1_0000..=0xffff_ffff => {
+        self.bytes.push(0xce);
+        self.bytes.extend((value as u32).to_be_bytes());
+      }
+      0x1_0000_0000.. => {
+        self.bytes.push(0xcf);
+        self.bytes.extend((value as u64).to_be_bytes());
+      }
+      -0x80..=-33 => self.bytes.extend([
+        0xd0,
+        value as i8 as u8,
+      ]),
+      -0x8000..=-0x81 => {
+        self.bytes.push(0xd1);
+        self.bytes.extend((value as i16).to_be_bytes());
+      }
+      -0x8000_0000..=-0x8001 => {
+        self.bytes.push(0xd2);
+        self.bytes.extend((value as i32).to_be_bytes());
+      }
+      _ => {
+        self.bytes.push(0xd3);
+        self.bytes.extend((value as i64).to_be_bytes());
+      }
+    }
+  }
+
+  fn value(&mut self, value: &Value) {
+    match value {
+      Value::Null => self.bytes.push(0xc0),
+      Value::Bool(false) => self.bytes.push(0xc2),
+      Value::Bool(true) => self.bytes.push(0xc3),
+      Value::Number(number) => match (number.as_i64(), number.as_u64(), number.as_f64()) {
+        (Some(value), _, _) => self.integer(value as i128),
+        (_, Some(value), _) => self.integer(value as i128),
+        (_, _, Some(value)) => {
+          self.bytes.push(0xcb);
+          self.bytes.extend(value.to_bits().to_be_bytes());
+        }
+        _ => self.bytes.push(0xc0),
+      },
+      Value::String(string) => self.string(string),
+      Value::Array(values) => {
+        self.header(values.len(), 0x90, 0xdc);
+        values.iter().for_each(|value| self.value(value));
+      }
+      Value::Object(map) => {
+        self.header(map.len(), 0x80, 0xde);
+        for (key, value) in map {
+          self.string(key);
+          self.value(value);
+        }
+      }
+    }
+  }
+}
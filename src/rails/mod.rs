@@ -1,9 +1,17 @@
-use base64::prelude::*;
-use rails_cookie_parser::ParseCookieError;
-use rails_cookie_parser::RailsCookieParser;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
+pub mod credentials;
+pub mod crypto;
+pub mod encoder;
+pub mod envelope;
+pub mod marshal;
+pub mod message_pack;
+pub mod serializer;
 pub mod versions;
+use crypto::{CookieCipher, CookieSalts, KeyGenerator};
+use envelope::Envelope;
+use serializer::Serializer;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RailsMessage {
@@ -16,28 +24,133 @@ pub struct RailsCookie {
   pub rails: RailsMessage,
 }
 
-pub fn decipher_cookie(rails_version: &str, cookie: &str) -> Result<String, ParseCookieError> {
-  let version = rails_version
-    .split(".")
-    .map(|x| x.parse().unwrap())
-    .collect::<Vec<u8>>();
-  let cookie_parser = match version[..] {
-    [6, _, _] => RailsCookieParser::default_rails6(),
-    [7, _, _] => RailsCookieParser::default_rails7(),
-    _ => RailsCookieParser::default(),
-  };
+/// Decrypts an encrypted cookie with `SECRET_KEY_BASE` from the environment.
+///
+/// The purpose embedded in the cookie is checked against its name, as Rails
+/// does, so that a cookie replayed under another name is rejected, and so is
+/// its expiry, so that an expired cookie is rejected too.
+///
+/// # Examples
+/// ```
+/// use chrono::{TimeDelta, Utc};
+/// use rails_cookies_monster::rails::crypto::{CookieCipher, CookieSalts, KeyGenerator};
+/// use rails_cookies_monster::rails::decipher_cookie;
+/// use rails_cookies_monster::rails::encoder::CookieEncoder;
+/// use rails_cookies_monster::rails::serializer::Serializer;
+///
+/// std::env::set_var("SECRET_KEY_BASE", "rails-cookies-everywhere");
+/// let encoder = CookieEncoder::new("8.0.1", "rails-cookies-everywhere");
+/// let decipher = |cookie: &str| {
+///   decipher_cookie(
+///     "8.0.1",
+///     Serializer::Json,
+///     CookieCipher::Aes256Gcm,
+///     KeyGenerator::for_version("8.0.1"),
+///     &CookieSalts::default(),
+///     "encrypted",
+///     cookie,
+///   )
+/// };
+/// let tomorrow = Utc::now() + TimeDelta::days(1);
+/// let envelope = encoder.envelope("encrypted", "canary".into(), Some(tomorrow));
+/// assert!(decipher(&encoder.encrypted(&envelope).unwrap()).is_ok());
+///
+/// let yesterday = Utc::now() - TimeDelta::days(1);
+/// let envelope = encoder.envelope("encrypted", "canary".into(), Some(yesterday));
+/// let err = decipher(&encoder.encrypted(&envelope).unwrap()).unwrap_err();
+/// assert!(err.starts_with("Message expired at"));
+/// ```
+pub fn decipher_cookie(
+  rails_version: &str,
+  serializer: Serializer,
+  cipher: CookieCipher,
+  key_generator: KeyGenerator,
+  salts: &CookieSalts,
+  cookie_name: &str,
+  cookie: &str,
+) -> Result<Envelope, String> {
+  let secret_key_base = std::env::var("SECRET_KEY_BASE").unwrap_or_default();
+  let envelope = decipher_envelope(
+    serializer,
+    cipher,
+    key_generator,
+    salts,
+    &secret_key_base,
+    cookie,
+  )?;
+  envelope.verify_purpose(expected_purpose(rails_version, cookie_name).as_deref())?;
+  envelope.verify_expiry(Utc::now())?;
+  Ok(envelope)
+}
 
-  let decoded = cookie_parser.decipher_cookie(cookie).unwrap();
-  let rails_message = decoded
-    .split('"')
-    .nth(5)
-    .expect("No rails message")
-    .to_string();
-  // Be safer, bro!
-  //
-  // let rails_cookie: RailsCookie = serde_json::from_str(&decoded).expect("Incorrect JSON");
-  // let rails_message = rails_cookie.rails.message
-  let b64 = BASE64_STANDARD.decode(rails_message).expect("Wrong base64");
+/// The purpose Rails gives the signed and encrypted cookies named `cookie_name`.
+///
+/// Cookies carry their purpose from Rails 6.0, `cookie.<name>`, there is none before.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::expected_purpose;
+///
+/// assert_eq!(expected_purpose("8.0.1", "signed").as_deref(), Some("cookie.signed"));
+/// assert_eq!(expected_purpose("5.2.8", "signed"), None);
+/// ```
+pub fn expected_purpose(rails_version: &str, cookie_name: &str) -> Option<String> {
+  let major: u64 = rails_version.split('.').next()?.parse().ok()?;
+  (major >= 6).then(|| format!("cookie.{}", cookie_name))
+}
 
-  Ok(String::from_utf8(b64).expect("Wrong UTF8"))
+/// Decrypts an encrypted cookie with `cipher` and keys derived by
+/// `key_generator` for `salts`, and unwraps it with the cookies serializer of
+/// the app.
+///
+/// The cipher, key generator and salts are those of the app, see
+/// [`KeyGenerator::for_version`] for the defaults of a Rails version.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::crypto::{CookieCipher, CookieSalts, KeyDigest, KeyGenerator};
+/// use rails_cookies_monster::rails::decipher_envelope;
+/// use rails_cookies_monster::rails::serializer::Serializer;
+///
+/// // Synthetic, not a capture: written as Rails 8.0 writes it with
+/// // `use_authenticated_cookie_encryption = false`, under a fixed IV
+/// let cookie = "ZkREM2ttK1RUMEJVSkJOYzZtclVDSU1NcGtjVlNrNStkaHBnZTU0TVNHd2YrMXV3QWtwSGhMaEkybEhncy9mVWw5bml0NkY3QzRWM0wvTW1GZjFKUHlCSDQvOTJUYnRmLzlUYWY4U1FwOWVMNlVFaGdTa1dWTzJiVXVsWldTM3Z2UTA3amloRWcrQitzWGRONG9ZNElnPT0tLUFBRUNBd1FGQmdjSUNRb0xEQTBPRHc9PQ==--653267e090a231d63e373e25b472d03250eb20e5";
+/// let secret = "rails-cookies-everywhere";
+/// let (legacy, key_generator) = (CookieCipher::LegacyAes256Cbc, KeyGenerator::for_version("8.0.1"));
+/// let salts = CookieSalts::default();
+/// let envelope = decipher_envelope(Serializer::Json, legacy, key_generator, &salts, secret, cookie).unwrap();
+/// assert_eq!(envelope.message, "correct-horse-battery-staple");
+/// assert_eq!(envelope.purpose.as_deref(), Some("cookie.legacy"));
+///
+/// // Captured from Rails 8.0.1, as in `cookies/v8.0.1`
+/// let captured = "hcw5ar/+Sn66hRanpLRxzERWgdYXYVSLc/RWthjerBQ8lW1iLLPALByfQRFHis4jAuwSyv6Tg025xowjlZxoiQk962Rou/R3Nf57VEUfn+OU38eZSe/dtCY6fABhWoHJiEW7eNE=--EZ4z+6ScDmx0+hha--EGiN2Ywd/PHzixGeVBzh+g==";
+/// let gcm = CookieCipher::Aes256Gcm;
+/// let envelope = decipher_envelope(Serializer::Json, gcm, key_generator, &salts, secret, captured).unwrap();
+/// assert_eq!(envelope.message, "correct-horse-battery-staple");
+/// assert_eq!(envelope.purpose.as_deref(), Some("cookie.encrypted"));
+///
+/// assert!(decipher_envelope(Serializer::Json, gcm, key_generator, &salts, secret, cookie).is_err());
+/// let sha1 = KeyGenerator::new(KeyDigest::Sha1, 1000);
+/// assert!(decipher_envelope(Serializer::Json, legacy, sha1, &salts, secret, cookie).is_err());
+///
+/// // Synthetic too, as with `authenticated_encrypted_cookie_salt = "monster salt"`
+/// let cookie = "4CggQntXqWhI3Wjc/m6/3V5s3lXWR85WoymZR6mgC5WWAYCAcrIeEHaQXzuGjtrHkNu8tRjqNG4Ycm1SJB5ny5sy+PY08O3O--AAECAwQFBgcICQoL--FQ22G/ng7Og7RuDo9vhJNg==";
+/// assert!(decipher_envelope(Serializer::Json, gcm, key_generator, &salts, secret, cookie).is_err());
+/// let salted = CookieSalts {
+///   authenticated_encrypted: "monster salt".to_string(),
+///   ..CookieSalts::default()
+/// };
+/// let envelope = decipher_envelope(Serializer::Json, gcm, key_generator, &salted, secret, cookie).unwrap();
+/// assert_eq!(envelope.purpose.as_deref(), Some("cookie.salted"));
+/// ```
+pub fn decipher_envelope(
+  serializer: Serializer,
+  cipher: CookieCipher,
+  key_generator: KeyGenerator,
+  salts: &CookieSalts,
+  secret_key_base: &str,
+  cookie: &str,
+) -> Result<Envelope, String> {
+  let decoded = cipher.decrypt(key_generator, salts, secret_key_base, cookie)?;
+  Envelope::parse(&decoded, serializer)
 }
@@ -0,0 +1,107 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{marshal, message_pack};
+
+/// A serializer of signed and encrypted cookies, as set by
+/// `config.action_dispatch.cookies_serializer`.
+///
+/// `hybrid` writes JSON and reads Marshal too, to migrate apps away from
+/// Marshal. `message_pack` is available from Rails 7.1 and reads JSON too.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rails::serializer::Serializer;
+///
+/// let hybrid: Serializer = "hybrid".parse().unwrap();
+/// assert_eq!(hybrid.load(b"\x04\x08i\x06").unwrap(), 1);
+/// assert_eq!(hybrid.load(b"1").unwrap(), 1);
+/// assert_eq!(hybrid.dump(&1.into()), b"1");
+/// assert!(Serializer::Json.load(b"\x04\x08i\x06").is_err());
+/// assert!(!Serializer::MessagePack.supports("7.0.8"));
+/// ```
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Serializer {
+  #[default]
+  Json,
+  Marshal,
+  Hybrid,
+  MessagePack,
+}
+
+impl FromStr for Serializer {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    Self::ALL
+      .into_iter()
+      .find(|serializer| serializer.name() == name)
+      .ok_or_else(|| format!("Unknown cookies serializer: {}", name))
+  }
+}
+
+impl Serializer {
+  pub const ALL: [Serializer; 4] = [
+    Serializer::Json,
+    Serializer::Marshal,
+    Serializer::Hybrid,
+    Serializer::MessagePack,
+  ];
+
+  /// The name of the serializer, as a Ruby symbol without its colon.
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Json => "json",
+      Self::Marshal => "marshal",
+      Self::Hybrid => "hybrid",
+      Self::MessagePack => "message_pack",
+    }
+  }
+
+  /// Whether `rails_version` has the serializer.
+  pub fn supports(&self, rails_version: &str) -> bool {
+    let mut parts = rails_version
+      .split('.')
+      .map(|part| part.parse::<u64>().unwrap_or(0));
+    let version = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    match self {
+      Self::MessagePack => version >= (7, 1),
+      _ => true,
+    }
+  }
+
+  /// The serializer that wrote `dumped`, told by the first bytes.
+  ///
+  /// Anything but Marshal or MessagePack is taken as JSON.
+  pub fn detect(dumped: &[u8]) -> Self {
+    if marshal::is_marshal(dumped) {
+      Self::Marshal
+    } else if message_pack::is_message_pack(dumped) {
+      Self::MessagePack
+    } else {
+      Self::Json
+    }
+  }
+
+  /// Serializes `value` as Rails writes it with this serializer: `hybrid`
+  /// writes JSON.
+  pub fn dump(&self, value: &Value) -> Vec<u8> {
+    match self {
+      Self::Json | Self::Hybrid => value.to_string().into_bytes(),
+      Self::Marshal => marshal::dump(value),
+      Self::MessagePack => message_pack::dump(value),
+    }
+  }
+
+  /// Deserializes `dumped` as Rails would read it with this serializer.
+  pub fn load(&self, dumped: &[u8]) -> Result<Value, String> {
+    match (self, Self::detect(dumped)) {
+      (Self::Marshal, _) | (Self::Hybrid, Self::Marshal) => marshal::load(dumped),
+      (Self::MessagePack, Self::MessagePack) => message_pack::load(dumped),
+      _ => serde_json::from_slice(dumped).map_err(|err| format!("Invalid JSON message: {}", err)),
+    }
+  }
+}
@@ -0,0 +1,244 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::profile::{secret_label, Profile};
+use crate::rails::crypto::{CookieCipher, KeyDigest, KeyGenerator};
+use crate::rails::encoder::CookieEncoder;
+use crate::scenarios::ruby_string;
+use crate::CapturedCookie;
+
+/// An old configuration whose cookies an app still reads, and rewrites under
+/// its own, with `config.action_dispatch.cookies_rotations`.
+///
+/// * secret: The old secret key base, the app's own if unset
+/// * digest: The old key generator digest, the app's own if unset
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rotation {
+  pub secret: Option<String>,
+  pub digest: Option<KeyDigest>,
+}
+
+impl Rotation {
+  /// A short name for the rotation, used in profile labels.
+  pub fn label(&self) -> String {
+    let mut label = String::from("rotate");
+    if let Some(secret) = &self.secret {
+      label.push('-');
+      label.push_str(&secret_label(secret));
+    }
+    if let Some(digest) = self.digest {
+      label.push('-');
+      label.push_str(digest.name());
+    }
+    label
+  }
+
+  /// The encoder of the old configuration, as an app booted on
+  /// `rails_version` with `profile` and `secret_key_base` reads it: the
+  /// app's own, with the secret and digest of the rotation.
+  pub fn encoder(
+    &self,
+    profile: &Profile,
+    rails_version: &str,
+    secret_key_base: &str,
+  ) -> CookieEncoder {
+    let secret_key_base = self.secret.as_deref().unwrap_or(secret_key_base);
+    let mut encoder = profile.encoder(rails_version, secret_key_base);
+    if let Some(digest) = self.digest {
+      encoder.key_generator = KeyGenerator::new(digest, KeyGenerator::ITERATIONS);
+    }
+    encoder
+  }
+
+  /// The Ruby block registering the rotation for the signed and encrypted
+  /// jars of an app encrypting its cookies with `cipher`.
+  ///
+  /// Keys are derived as the app would have under the old configuration.
+  pub fn initializer(&self, cipher: CookieCipher) -> String {
+    let secret = match &self.secret {
+      Some(secret) => ruby_string(secret),
+      None => "Rails.application.secret_key_base".to_string(),
+    };
+    let digest = match self.digest {
+      Some(digest) => format!(", hash_digest_class: {}", digest.ruby_class()),
+      None => String::new(),
+    };
+    let encrypted = match cipher.is_authenticated() {
+      true => format!(
+        "key_generator.generate_key(config.authenticated_encrypted_cookie_salt, \
+         ActiveSupport::MessageEncryptor.key_len({:?})), cipher: {:?}",
+        cipher.cipher(),
+        cipher.cipher()
+      ),
+      false => "key_generator.generate_key(config.encrypted_cookie_salt, \
+                ActiveSupport::MessageEncryptor.key_len(\"aes-256-cbc\")), \
+                key_generator.generate_key(config.encrypted_signed_cookie_salt), \
+                cipher: \"aes-256-cbc\""
+        .to_string(),
+    };
+    format!(
+      "Rails.application.config.after_initialize do\n  \
+         config = Rails.application.config.action_dispatch\n  \
+         key_generator = ActiveSupport::KeyGenerator.new({}, iterations: 1000{})\n  \
+         config.cookies_rotations.tap do |cookies|\n    \
+           cookies.rotate :signed, key_generator.generate_key(config.signed_cookie_salt)\n    \
+           cookies.rotate :encrypted, {}\n  \
+         end\n\
+       end\n",
+      secret, digest, encrypted
+    )
+  }
+}
+
+/// Cookies set under one configuration, read by an app rotating them.
+///
+/// * name: The name of the flow
+/// * from: The configuration setting the cookies
+/// * to: The configuration reading them, with a rotation for `from`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RotationFlow {
+  pub name: String,
+  pub from: Profile,
+  pub to: Profile,
+}
+
+/// The flows of the rotation suite (`ROTATION_SUITE`), one per kind:
+/// * `secret`: From `<secret>-old` to `secret`
+/// * `digest`: From a SHA1 key generator to SHA256, as apps upgraded to Rails 7.0 do
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::rotation::rotation_suite;
+///
+/// let flows = rotation_suite(&["secret", "digest"], "rails-cookies-everywhere");
+/// assert_eq!(flows.len(), 2);
+/// assert_eq!(flows[1].from.label(), "production-sha1");
+/// assert_eq!(flows[1].to.label(), "production-sha256-rotate-sha1");
+/// assert!(flows[1].to.initializer().contains("hash_digest_class: OpenSSL::Digest::SHA1"));
+/// ```
+pub fn rotation_suite(kinds: &[&str], secret: &str) -> Vec<RotationFlow> {
+  let mut flows = vec![];
+  for kind in kinds {
+    let (from, to) = match *kind {
+      "secret" => {
+        let old = format!("{}-old", secret);
+        let from = Profile {
+          secret: Some(old.clone()),
+          ..Profile::default()
+        };
+        let to = Profile {
+          rotations: vec![Rotation {
+            secret: Some(old),
+            digest: None,
+          }],
+          ..Profile::default()
+        };
+        (from, to)
+      }
+      "digest" => {
+        let from = Profile {
+          digest: Some(KeyDigest::Sha1),
+          ..Profile::default()
+        };
+        let to = Profile {
+          digest: Some(KeyDigest::Sha256),
+          rotations: vec![Rotation {
+            secret: None,
+            digest: Some(KeyDigest::Sha1),
+          }],
+          ..Profile::default()
+        };
+        (from, to)
+      }
+      kind => {
+        warn!("Ignoring unknown rotation: {}", kind);
+        continue;
+      }
+    };
+    flows.push(RotationFlow {
+      name: kind.to_string(),
+      from,
+      to,
+    });
+  }
+  flows
+}
+
+/// The cookies of a rotation flow on a Rails version.
+///
+/// * name: The name of the flow
+/// * rails_version: The Rails version of both apps
+/// * from: The configuration that set the cookies
+/// * to: The configuration that read them
+/// * sent: The cookies set under `from`, sent to `to`
+/// * read: The values `to` read from the sent cookies, by scenario
+/// * upgraded: The cookies `to` set back, rewritten under its own configuration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RotationCapture {
+  pub name: String,
+  pub rails_version: String,
+  pub from: Profile,
+  pub to: Profile,
+  pub sent: Vec<CapturedCookie>,
+  pub read: Map<String, Value>,
+  pub upgraded: Vec<CapturedCookie>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{rotation_suite, Rotation};
+  use crate::profile::Profile;
+  use crate::rails::decipher_envelope;
+  use crate::rails::encoder::tests::captured;
+  use crate::rails::encoder::CookieEncoder;
+
+  /// Whether the app of `encoder` reads the encrypted cookie captured from
+  /// Rails 8.0.1 with the secret `rails-cookies-everywhere`.
+  fn reads_capture(encoder: &CookieEncoder) -> bool {
+    decipher_envelope(
+      encoder.serializer,
+      encoder.cipher,
+      encoder.key_generator,
+      &encoder.salts,
+      &encoder.secret_key_base,
+      &captured()["encrypted"],
+    )
+    .is_ok_and(|envelope| envelope.message == "correct-horse-battery-staple")
+  }
+
+  #[test]
+  fn secret_rotation_reads_rails() {
+    // The capture was set under the old secret, the app has a new one.
+    let to = Profile {
+      rotations: vec![Rotation {
+        secret: Some("rails-cookies-everywhere".to_string()),
+        digest: None,
+      }],
+      ..Profile::default()
+    };
+    let secret = "rails-cookies-rotated";
+    assert!(!reads_capture(&to.encoder("8.0.1", secret)));
+    assert!(reads_capture(&to.rotations[0].encoder(
+      &to,
+      "8.0.1",
+      secret
+    )));
+  }
+
+  #[test]
+  fn digest_rotation_reads_rails() {
+    // The capture was set with the SHA256 key generator the app upgrades to.
+    let flow = rotation_suite(&["digest"], "rails-cookies-everywhere").remove(0);
+    let secret = "rails-cookies-everywhere";
+    assert!(!reads_capture(&flow.from.encoder("8.0.1", secret)));
+    assert!(reads_capture(&flow.to.encoder("8.0.1", secret)));
+    let rotated = flow.to.rotations[0].encoder(&flow.to, "8.0.1", secret);
+    assert!(!reads_capture(&rotated));
+    assert_eq!(
+      Profile::default().encoder("8.0.1", secret),
+      flow.to.encoder("8.0.1", secret)
+    );
+  }
+}
@@ -0,0 +1,568 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use urlencoding::{decode, encode};
+
+use super::{ContainerRuntime, ContainerSpec, ContainerState, ImageBuild};
+use crate::flows::FlowStep;
+use crate::rails::crypto::verify_cookie;
+use crate::rails::encoder::CookieEncoder;
+use crate::rails::envelope::Envelope;
+use crate::rails::{credentials, decipher_envelope, expected_purpose};
+use crate::scenarios;
+
+const VERSION_IMAGE_PREFIX: &str = "rails-cookies-everywhere:rails-v";
+
+#[derive(Debug)]
+struct FakeContainer {
+  spec: ContainerSpec,
+  rails_version: String,
+  address: Option<String>,
+  exit_code: Option<i64>,
+  server: Option<JoinHandle<()>>,
+}
+
+/// An in-process runtime serving canned responses, for tests.
+///
+/// Building an image only records its tag, and starting a container binds a
+/// small HTTP server on a free local port. That server answers the routes of
+/// the Rails app as an app of the default profile would, see [`FakeApp`]: `/`
+/// and the scenario routes set the fixture cookies of its version, whatever
+/// the scenarios and the profile.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::runtime::fake::FakeRuntime;
+/// use rails_cookies_monster::RailsCookiesMonster;
+/// use std::sync::Arc;
+///
+/// let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/cookies");
+/// let runtime = FakeRuntime::new()
+///   .with_fixtures(fixtures.as_ref())
+///   .with_exit("8.0.0", 1);
+/// let monster = RailsCookiesMonster::with_runtime(Arc::new(runtime));
+/// ```
+#[derive(Debug, Default)]
+pub struct FakeRuntime {
+  cookies: HashMap<String, Vec<String>>,
+  exits: HashMap<String, i64>,
+  images: Mutex<HashSet<String>>,
+  containers: Mutex<HashMap<String, FakeContainer>>,
+  networks: Mutex<HashMap<String, HashMap<String, String>>>,
+  next_id: AtomicUsize,
+}
+
+impl FakeRuntime {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Sets the `Set-Cookie` headers served for a Rails version.
+  pub fn with_cookies(mut self, rails_version: &str, cookies: Vec<String>) -> Self {
+    self.cookies.insert(rails_version.to_owned(), cookies);
+    self
+  }
+
+  /// Loads the cookies of every `v<version>` file of a fixtures directory.
+  ///
+  /// Each line of a fixture is a `name=value` pair, as in the `cookies`
+  /// directory of this repository.
+  pub fn with_fixtures(mut self, directory: &Path) -> Self {
+    let entries = std::fs::read_dir(directory).expect("Could not read fixtures directory");
+    for entry in entries.flatten() {
+      let file_name = entry.file_name().to_string_lossy().to_string();
+      let Some(rails_version) = file_name.strip_prefix('v') else {
+        continue;
+      };
+      let contents = std::fs::read_to_string(entry.path()).expect("Could not read fixture");
+      let cookies = contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| format!("{}; path=/; httponly", line))
+        .collect();
+      self.cookies.insert(rails_version.to_owned(), cookies);
+    }
+    self
+  }
+
+  /// Makes the containers of a Rails version exit on boot with `exit_code`.
+  pub fn with_exit(mut self, rails_version: &str, exit_code: i64) -> Self {
+    self.exits.insert(rails_version.to_owned(), exit_code);
+    self
+  }
+}
+
+/// The cookies a route of a [`FakeApp`] sets, and what it reports besides the
+/// app itself.
+type Response = (Vec<String>, Map<String, Value>);
+
+/// The Rails app a fake container serves, booted from its spec.
+///
+/// Cookies are read and written under the secret of the app, with the
+/// defaults of its version: the rest of the profile is not applied. The jar
+/// a cookie is read from is told by its value.
+///
+/// * environment: The Rails environment of the app
+/// * canary: The value the app stores, from `CANARY_VALUE`
+/// * encoder: Writes and reads the cookies of the app
+/// * credentials: The master key and encrypted credentials the secret is
+///   read from, when the spec has no `SECRET_KEY_BASE`
+/// * cookies: The `Set-Cookie` headers of `/` and the scenario routes
+#[derive(Debug)]
+struct FakeApp {
+  environment: String,
+  canary: String,
+  encoder: CookieEncoder,
+  credentials: Option<(String, String)>,
+  cookies: Vec<String>,
+}
+
+impl FakeApp {
+  fn new(rails_version: &str, spec: &ContainerSpec, cookies: Vec<String>) -> Result<Self, String> {
+    let env = |key: &str| {
+      spec
+        .env
+        .iter()
+        .find_map(|env| env.strip_prefix(&format!("{}=", key)))
+        .map(str::to_owned)
+    };
+    let environment = env("RAILS_ENV").unwrap_or_default();
+    let generate = || {
+      format!(
+        "{}{}",
+        credentials::generate_key(),
+        credentials::generate_key()
+      )
+    };
+    let (secret_key_base, credentials) = match env("SECRET_KEY_BASE") {
+      Some(secret_key_base) => (secret_key_base, None),
+      None => {
+        let (secret_key_base, master_key) = (generate(), credentials::generate_key());
+        let content = credentials::encrypt(
+          &master_key,
+          &format!("secret_key_base: {}\n", secret_key_base),
+        )?;
+        (secret_key_base, Some((master_key, content)))
+      }
+    };
+    // Development and test apps generate their secret, as Rails does.
+    let secret_key_base = match environment.as_str() {
+      "production" => secret_key_base,
+      _ => generate(),
+    };
+    Ok(Self {
+      environment,
+      canary: env("CANARY_VALUE").unwrap_or_default(),
+      encoder: CookieEncoder::new(rails_version, &secret_key_base),
+      credentials,
+      cookies,
+    })
+  }
+
+  /// The HTTP response to a request, as its raw head.
+  fn respond(&self, request: &str) -> String {
+    let mut lines = request.lines();
+    let target = lines
+      .next()
+      .and_then(|line| line.split_whitespace().nth(1))
+      .unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers: HashMap<String, &str> = lines
+      .filter_map(|line| line.split_once(':'))
+      .map(|(name, value)| (name.trim().to_lowercase(), value.trim()))
+      .collect();
+    let cookies: BTreeMap<&str, String> = headers
+      .get("cookie")
+      .into_iter()
+      .flat_map(|header| header.split(';'))
+      .filter_map(|pair| pair.trim().split_once('='))
+      .map(|(name, value)| {
+        (
+          name,
+          decode(value).map_or(value.to_owned(), |v| v.into_owned()),
+        )
+      })
+      .collect();
+
+    let (status, set_cookies, body) = match self.route(path, query, &headers, &cookies) {
+      Ok(Some((set_cookies, extra))) => {
+        let mut report = json!({
+          "version": self.encoder.rails_version,
+          "environment": self.environment,
+          "secret_key_base": self.encoder.secret_key_base,
+        });
+        if let Some((master_key, content)) = &self.credentials {
+          report["credentials"] = json!({ "master_key": master_key, "content": content });
+        }
+        report.as_object_mut().unwrap().extend(extra);
+        ("200 OK", set_cookies, report.to_string())
+      }
+      Ok(None) => ("404 Not Found", vec![], json!({}).to_string()),
+      Err(err) => (
+        "500 Internal Server Error",
+        vec![],
+        json!({ "error": err }).to_string(),
+      ),
+    };
+    let set_cookies: String = set_cookies
+      .iter()
+      .map(|cookie| format!("Set-Cookie: {}\r\n", cookie))
+      .collect();
+    format!(
+      "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+      status,
+      body.len(),
+      set_cookies,
+      body
+    )
+  }
+
+  /// The response of the route at `path`, `None` for an unknown route.
+  fn route(
+    &self,
+    path: &str,
+    query: &str,
+    headers: &HashMap<String, &str>,
+    cookies: &BTreeMap<&str, String>,
+  ) -> Result<Option<Response>, String> {
+    let report = |value: Value| match value {
+      Value::Object(report) => report,
+      _ => Map::new(),
+    };
+    let response = match path {
+      "/" => (self.cookies.clone(), Map::new()),
+      path if path.starts_with("/scenarios/") => (self.cookies.clone(), Map::new()),
+      scenarios::READ_ROUTE => (vec![], report(json!({ "cookies": self.read(cookies) }))),
+      scenarios::ACCEPT_ROUTE => {
+        let name = query
+          .split('&')
+          .find_map(|param| param.strip_prefix("name="))
+          .and_then(|name| decode(name).ok())
+          .unwrap_or_default();
+        let cookie = cookies.get(name.as_ref());
+        let mut session = self.session(cookies);
+        // A session is started when the cookie store rejects the one sent.
+        if !session.contains_key("session_id") {
+          session.insert("session_id".to_string(), credentials::generate_key().into());
+        }
+        let accepted = json!({
+          "signed": cookie.map_or(Value::Null, |cookie| self.signed(&name, cookie)),
+          "encrypted": cookie.map_or(Value::Null, |cookie| self.encrypted(&name, cookie)),
+          "session": session,
+        });
+        (vec![], report(json!({ "accepted": accepted })))
+      }
+      scenarios::ENCRYPTED_FILE_ROUTE => {
+        let header = |name: &str| {
+          headers
+            .get(&name.to_lowercase())
+            .copied()
+            .unwrap_or_default()
+        };
+        let key = header(scenarios::ENCRYPTED_FILE_KEY_HEADER);
+        let read = credentials::decrypt(key, header(scenarios::ENCRYPTED_FILE_HEADER)).ok();
+        let written = credentials::encrypt(key, &format!("canary: {}\n", self.canary))?;
+        let encrypted_file = json!({ "read": read, "written": written });
+        (vec![], report(json!({ "encrypted_file": encrypted_file })))
+      }
+      path => match FlowStep::ALL.iter().find(|step| step.route() == path) {
+        Some(step) => self.flow_step(*step, cookies)?,
+        None => return Ok(None),
+      },
+    };
+    Ok(Some(response))
+  }
+
+  /// What [`scenarios::READ_ROUTE`] reads from `cookies`, by scenario: the
+  /// value of each cookie, decoded through the jar that wrote it, and the
+  /// entries of the session.
+  fn read(&self, cookies: &BTreeMap<&str, String>) -> Map<String, Value> {
+    let mut read = Map::new();
+    for (name, value) in cookies {
+      if *name == scenarios::SESSION_COOKIE {
+        let session = self.session(cookies).into_iter();
+        read.extend(session.filter(|(key, _)| key != "session_id"));
+        continue;
+      }
+      let decoded = match self.encrypted(name, value) {
+        Value::Null => self.signed(name, value),
+        decoded => decoded,
+      };
+      let decoded = match decoded {
+        // Signed and encrypted values are `data--digest`, plain ones are read as is.
+        Value::Null if !value.contains("--") => value.as_str().into(),
+        decoded => decoded,
+      };
+      read.insert(name.to_string(), decoded);
+    }
+    read
+  }
+
+  /// What `cookies.signed[name]` reads from `cookie`, `null` if rejected.
+  fn signed(&self, name: &str, cookie: &str) -> Value {
+    let encoder = &self.encoder;
+    verify_cookie(
+      encoder.key_generator,
+      &encoder.salts,
+      &encoder.secret_key_base,
+      cookie,
+    )
+    .and_then(|data| Envelope::parse(&data, encoder.serializer))
+    .and_then(|envelope| self.unwrap(name, envelope))
+    .unwrap_or(Value::Null)
+  }
+
+  /// What `cookies.encrypted[name]` reads from `cookie`, `null` if rejected.
+  fn encrypted(&self, name: &str, cookie: &str) -> Value {
+    let encoder = &self.encoder;
+    decipher_envelope(
+      encoder.serializer,
+      encoder.cipher,
+      encoder.key_generator,
+      &encoder.salts,
+      &encoder.secret_key_base,
+      cookie,
+    )
+    .and_then(|envelope| self.unwrap(name, envelope))
+    .unwrap_or(Value::Null)
+  }
+
+  /// The message of the envelope of the cookie `name`, once its purpose and
+  /// expiry are checked.
+  fn unwrap(&self, name: &str, envelope: Envelope) -> Result<Value, String> {
+    let purpose = expected_purpose(&self.encoder.rails_version, name);
+    envelope.verify_purpose(purpose.as_deref())?;
+    envelope.verify_expiry(Utc::now())?;
+    Ok(envelope.message)
+  }
+
+  /// The session the cookie store reads from `cookies`, empty without a
+  /// valid session cookie.
+  fn session(&self, cookies: &BTreeMap<&str, String>) -> Map<String, Value> {
+    let key = scenarios::SESSION_COOKIE;
+    match cookies.get(key).map(|cookie| self.encrypted(key, cookie)) {
+      Some(Value::Object(session)) => session,
+      _ => Map::new(),
+    }
+  }
+
+  /// Runs a step of the session flow, as the flows controller does.
+  fn flow_step(
+    &self,
+    step: FlowStep,
+    cookies: &BTreeMap<&str, String>,
+  ) -> Result<Response, String> {
+    let key = scenarios::SESSION_COOKIE;
+    let read = self.session(cookies);
+    let mut written = read.clone();
+    match step {
+      FlowStep::Create => {
+        written.insert("flow".to_string(), self.canary.as_str().into());
+      }
+      FlowStep::Mutate => {
+        let flow = read.get("flow").and_then(Value::as_str).unwrap_or_default();
+        written.insert("flow".to_string(), format!("{}-mutated", flow).into());
+      }
+      FlowStep::Reset => written.clear(),
+      FlowStep::Delete => {
+        let deleted = format!(
+          "{}=; path=/; max-age=0; expires=Thu, 01 Jan 1970 00:00:00 GMT",
+          key
+        );
+        return Ok((vec![deleted], Map::new()));
+      }
+    }
+    let cookie = self.encoder.session(key, &written)?;
+    let set_cookie = format!(
+      "{}={}; path=/; httponly; samesite=lax",
+      key,
+      encode(&cookie)
+    );
+    let mut report = Map::new();
+    report.insert(
+      "session".to_string(),
+      json!({ "read": read, "written": written }),
+    );
+    Ok((vec![set_cookie], report))
+  }
+}
+
+#[async_trait]
+impl ContainerRuntime for FakeRuntime {
+  async fn list_images(&self) -> Result<Vec<String>, String> {
+    Ok(self.images.lock().unwrap().iter().cloned().collect())
+  }
+
+  async fn build_image(&self, build: &ImageBuild) -> Result<(), String> {
+    self.images.lock().unwrap().insert(build.tag.clone());
+    Ok(())
+  }
+
+  async fn create_container(&self, spec: &ContainerSpec) -> Result<String, String> {
+    if !self.images.lock().unwrap().contains(&spec.image) {
+      return Err(format!("No such image: {}", spec.image));
+    }
+    let rails_version = spec
+      .image
+      .strip_prefix(VERSION_IMAGE_PREFIX)
+      .unwrap_or_default()
+      .to_owned();
+    let id = format!("fake-{}", self.next_id.fetch_add(1, Ordering::SeqCst));
+    let container = FakeContainer {
+      spec: spec.clone(),
+      rails_version,
+      address: None,
+      exit_code: None,
+      server: None,
+    };
+    self
+      .containers
+      .lock()
+      .unwrap()
+      .insert(id.clone(), container);
+    Ok(id)
+  }
+
+  async fn start_container(&self, container_id: &str) -> Result<(), String> {
+    let (rails_version, spec) = match self.containers.lock().unwrap().get(container_id) {
+      Some(container) => (container.rails_version.clone(), container.spec.clone()),
+      None => return Err(format!("No such container: {}", container_id)),
+    };
+
+    if let Some(exit_code) = self.exits.get(&rails_version) {
+      let mut containers = self.containers.lock().unwrap();
+      containers.get_mut(container_id).unwrap().exit_code = Some(*exit_code);
+      return Ok(());
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+      .await
+      .map_err(|err| err.to_string())?;
+    let address = listener.local_addr().map_err(|err| err.to_string())?;
+    let cookies = self
+      .cookies
+      .get(&rails_version)
+      .cloned()
+      .unwrap_or_default();
+    let app = Arc::new(FakeApp::new(&rails_version, &spec, cookies)?);
+    let server = tokio::spawn(async move {
+      while let Ok((mut stream, _)) = listener.accept().await {
+        let app = app.clone();
+        tokio::spawn(async move {
+          let mut request = vec![];
+          let mut buffer = [0; 1024];
+          while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            match stream.read(&mut buffer).await {
+              Ok(0) | Err(_) => return,
+              Ok(read) => request.extend_from_slice(&buffer[..read]),
+            }
+          }
+          let response = app.respond(&String::from_utf8_lossy(&request));
+          let _ = stream.write_all(response.as_bytes()).await;
+        });
+      }
+    });
+
+    let mut containers = self.containers.lock().unwrap();
+    let container = containers.get_mut(container_id).unwrap();
+    container.address = Some(format!("localhost:{}", address.port()));
+    container.server = Some(server);
+    Ok(())
+  }
+
+  async fn inspect_container(&self, container_id: &str) -> Result<ContainerState, String> {
+    let containers = self.containers.lock().unwrap();
+    let Some(container) = containers.get(container_id) else {
+      return Err(format!("No such container: {}", container_id));
+    };
+    Ok(ContainerState {
+      running: container.server.is_some(),
+      exit_code: container.exit_code.unwrap_or_default(),
+      address: container.address.clone(),
+    })
+  }
+
+  async fn container_logs(&self, container_id: &str) -> Result<Vec<String>, String> {
+    let containers = self.containers.lock().unwrap();
+    let Some(container) = containers.get(container_id) else {
+      return Err(format!("No such container: {}", container_id));
+    };
+    let logs = match (&container.address, container.exit_code) {
+      (Some(address), _) => vec![format!(
+        "* Listening on http://{}",
+        address
+      )],
+      (None, Some(exit_code)) => vec![format!(
+        "Fake boot failure, exiting with {}",
+        exit_code
+      )],
+      (None, None) => vec![],
+    };
+    Ok(logs)
+  }
+
+  async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+    let Some(container) = self.containers.lock().unwrap().remove(container_id) else {
+      return Err(format!("No such container: {}", container_id));
+    };
+    if let Some(server) = container.server {
+      server.abort();
+    }
+    Ok(())
+  }
+
+  async fn labelled_containers(&self, label: &str) -> Result<Vec<(String, String)>, String> {
+    Ok(
+      self
+        .containers
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(id, container)| {
+          let value = container.spec.labels.get(label)?;
+          Some((id.clone(), value.clone()))
+        })
+        .collect(),
+    )
+  }
+
+  // Fake containers are only reachable in-process, networks are only recorded.
+  async fn create_network(
+    &self,
+    name: &str,
+    labels: &HashMap<String, String>,
+  ) -> Result<(), String> {
+    let mut networks = self.networks.lock().unwrap();
+    networks
+      .entry(name.to_owned())
+      .or_insert_with(|| labels.clone());
+    Ok(())
+  }
+
+  async fn labelled_networks(&self, label: &str) -> Result<Vec<(String, String)>, String> {
+    Ok(
+      self
+        .networks
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(name, labels)| Some((name.clone(), labels.get(label)?.clone())))
+        .collect(),
+    )
+  }
+
+  async fn remove_network(&self, name: &str) -> Result<(), String> {
+    match self.networks.lock().unwrap().remove(name) {
+      Some(_) => Ok(()),
+      None => Err(format!("No such network: {}", name)),
+    }
+  }
+}
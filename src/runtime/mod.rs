@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use log::trace;
+use tokio::time::{sleep, Duration, Instant};
+
+pub mod fake;
+pub mod native;
+
+/// Label set on every container and network created by a run, used to find
+/// leftovers.
+///
+/// Its value is the ID of the process that created them, so that a run only
+/// sweeps the containers and networks of runs that are gone.
+pub const RUN_LABEL: &str = "rails-cookies-monster";
+
+/// An image to build.
+///
+/// * tag: The full image tag, e.g. `rails-cookies-everywhere:rails-v8.0.1`
+/// * context: The tar archive holding the Dockerfile and its files
+/// * args: The build arguments
+#[derive(Clone, Debug)]
+pub struct ImageBuild {
+  pub tag: String,
+  pub context: PathBuf,
+  pub args: HashMap<String, String>,
+}
+
+/// A container to create.
+///
+/// * name: The container name
+/// * image: The full tag of the image to run
+/// * env: `KEY=value` environment variables
+/// * labels: Labels to find the container back with
+/// * network: The network to attach the container to, instead of publishing
+///   its port on the host
+/// * memory: The memory cap, in bytes
+/// * cpus: The CPU cap, in number of CPUs
+/// * files: Files written into the app before it boots, as paths relative to
+///   the app root with their contents
+#[derive(Clone, Debug, Default)]
+pub struct ContainerSpec {
+  pub name: String,
+  pub image: String,
+  pub env: Vec<String>,
+  pub labels: HashMap<String, String>,
+  pub network: Option<String>,
+  pub memory: Option<u64>,
+  pub cpus: Option<f64>,
+  pub files: Vec<(String, String)>,
+}
+
+/// What inspecting a container tells about it.
+///
+/// * running: Whether the container is still running
+/// * exit_code: The exit code, meaningful once the container stopped
+/// * address: The `host:port` the Rails server is reachable on, once started
+#[derive(Clone, Debug)]
+pub struct ContainerState {
+  pub running: bool,
+  pub exit_code: i64,
+  pub address: Option<String>,
+}
+
+/// Everything the monster needs from a container engine.
+///
+/// [`crate::docker::DockerRuntime`] talks to a real Docker daemon,
+/// [`native::NativeRuntime`] runs the apps on the locally installed Ruby, and
+/// [`fake::FakeRuntime`] serves the routes of the app in-process.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+  /// Lists the tags of the images available to run.
+  async fn list_images(&self) -> Result<Vec<String>, String>;
+
+  async fn build_image(&self, build: &ImageBuild) -> Result<(), String>;
+
+  /// Creates a container, returning its ID.
+  async fn create_container(&self, spec: &ContainerSpec) -> Result<String, String>;
+
+  async fn start_container(&self, container_id: &str) -> Result<(), String>;
+
+  async fn inspect_container(&self, container_id: &str) -> Result<ContainerState, String>;
+
+  /// Returns the logs (stdout and stderr) the container produced so far.
+  async fn container_logs(&self, container_id: &str) -> Result<Vec<String>, String>;
+
+  /// Removes a container, stopping it first if needed.
+  async fn remove_container(&self, container_id: &str) -> Result<(), String>;
+
+  /// Lists the IDs of all containers, running or not, carrying `label`, with
+  /// the value of the label.
+  async fn labelled_containers(&self, label: &str) -> Result<Vec<(String, String)>, String>;
+
+  /// Creates an internal network, without outbound access, unless it exists.
+  async fn create_network(
+    &self,
+    name: &str,
+    labels: &HashMap<String, String>,
+  ) -> Result<(), String>;
+
+  /// Lists the names of all networks carrying `label`, with the value of the
+  /// label.
+  async fn labelled_networks(&self, label: &str) -> Result<Vec<(String, String)>, String>;
+
+  async fn remove_network(&self, name: &str) -> Result<(), String>;
+}
+
+/// Waits for the Rails server of a container to accept connections.
+///
+/// Puma announces itself with a `Listening on` line once it is bound, so the
+/// container logs are polled with an exponential backoff until that line shows
+/// up. Fails early if the container exits, or once `timeout` has elapsed.
+pub(crate) async fn wait_until_ready(
+  runtime: &dyn ContainerRuntime,
+  container_id: &str,
+  timeout: Duration,
+) -> Result<(), String> {
+  let deadline = Instant::now() + timeout;
+  let mut delay = Duration::from_millis(250);
+  loop {
+    let state = runtime.inspect_container(container_id).await?;
+    if !state.running {
+      return Err(format!("Container exited with code {}", state.exit_code));
+    }
+
+    let logs = runtime.container_logs(container_id).await?;
+    if logs.iter().any(|line| line.contains("Listening on")) {
+      return Ok(());
+    }
+
+    let now = Instant::now();
+    if now >= deadline {
+      return Err(format!(
+        "Container not ready after {} seconds",
+        timeout.as_secs()
+      ));
+    }
+    trace!(
+      "Container {} not ready, retrying in {}ms",
+      container_id,
+      delay.as_millis()
+    );
+    sleep(delay.min(deadline - now)).await;
+    delay = (delay * 2).min(Duration::from_secs(5));
+  }
+}
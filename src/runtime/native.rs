@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use log::{debug, info, trace};
+use tokio::process::{Child, Command};
+
+use super::{ContainerRuntime, ContainerSpec, ContainerState, ImageBuild};
+
+const DOCKERFILE: &str = include_str!("../../docker/rails/Dockerfile");
+const CONTROLLER: &str = include_str!("../../docker/rails/rails_patch/rails_controller.rb");
+const ROUTES: &str = include_str!("../../docker/rails/rails_patch/rails_routes.rb");
+const VERSION_IMAGE_PREFIX: &str = "rails-cookies-everywhere:rails-v";
+const BUILT_MARKER: &str = ".rails-cookies-monster";
+
+#[derive(Debug)]
+struct NativeApp {
+  spec: ContainerSpec,
+  rails_version: String,
+  directory: PathBuf,
+  port: u16,
+  log_path: PathBuf,
+  server: Option<Child>,
+  exit_code: Option<i64>,
+}
+
+/// A runtime running the Rails apps on the locally installed Ruby.
+///
+/// Meant for machines without Docker: each Rails version gets the same
+/// `cookie-monster` app as the Docker images, created in a directory of its own
+/// under `root`, and each "container" is a `rails server` process on a free port.
+/// Containers given files run on a copy of the app of their own.
+///
+/// Ruby versions are selected through `RBENV_VERSION` and `ASDF_RUBY_VERSION`,
+/// so they must be installed with rbenv or asdf, except for `latest` which uses
+/// whatever Ruby is active.
+#[derive(Debug)]
+pub struct NativeRuntime {
+  root: PathBuf,
+  apps: Mutex<HashMap<String, NativeApp>>,
+  next_id: AtomicUsize,
+}
+
+impl Default for NativeRuntime {
+  fn default() -> Self {
+    Self::new(&std::env::temp_dir().join("rails-cookies-monster"))
+  }
+}
+
+impl NativeRuntime {
+  pub fn new(root: &Path) -> Self {
+    Self {
+      root: root.to_path_buf(),
+      apps: Mutex::new(HashMap::new()),
+      next_id: AtomicUsize::new(0),
+    }
+  }
+
+  /// The `rails new` flags of the Dockerfile, so both runtimes create the same app.
+  fn rails_new_flags() -> Vec<&'static str> {
+    DOCKERFILE
+      .split("rails new cookie-monster")
+      .nth(1)
+      .expect("No rails new command in the Dockerfile")
+      .lines()
+      .skip(1)
+      .map(|line| line.trim().trim_end_matches('\\').trim())
+      .take_while(|flag| flag.starts_with("--"))
+      .collect()
+  }
+
+  fn app_directory(&self, rails_version: &str) -> PathBuf {
+    self
+      .root
+      .join(format!("rails-v{}", rails_version))
+      .join("cookie-monster")
+  }
+
+  fn command(program: &str, ruby_version: &str) -> Command {
+    let mut command = Command::new(program);
+    if ruby_version != "latest" {
+      command
+        .env("RBENV_VERSION", ruby_version)
+        .env("ASDF_RUBY_VERSION", ruby_version);
+    }
+    command
+  }
+
+  async fn run(mut command: Command) -> Result<(), String> {
+    trace!("Running {:?}", command);
+    let output = command
+      .stdin(Stdio::null())
+      .output()
+      .await
+      .map_err(|err| err.to_string())?;
+    if output.status.success() {
+      return Ok(());
+    }
+    Err(format!(
+      "{:?} failed with {}: {}",
+      command.as_std(),
+      output.status,
+      String::from_utf8_lossy(&output.stderr).trim()
+    ))
+  }
+
+  /// Checks that the requested Ruby is available.
+  async fn build_base(&self, ruby_version: &str) -> Result<(), String> {
+    let mut ruby = Self::command("ruby", ruby_version);
+    ruby.arg("--version");
+    Self::run(ruby)
+      .await
+      .map_err(|err| format!("Ruby {} is not available: {}", ruby_version, err))
+  }
+
+  /// Installs Rails and creates the `cookie-monster` app, like the Dockerfile does.
+  async fn build_version(
+    &self,
+    ruby_version: &str,
+    rails_version: &str,
+    patch: &str,
+  ) -> Result<(), String> {
+    let app_directory = self.app_directory(rails_version);
+    let version_directory = app_directory.parent().unwrap();
+    if version_directory.exists() {
+      std::fs::remove_dir_all(version_directory).map_err(|err| err.to_string())?;
+    }
+    std::fs::create_dir_all(version_directory).map_err(|err| err.to_string())?;
+
+    info!("Installing Rails v{} natively", rails_version);
+    let mut gem = Self::command("gem", ruby_version);
+    gem.args([
+      "install",
+      "rails",
+      "--no-document",
+      "-v",
+      rails_version,
+    ]);
+    Self::run(gem).await?;
+    if patch == "7.0.x" {
+      self.patch_rails_7_0(ruby_version, rails_version).await?;
+    }
+
+    let mut rails = Self::command("rails", ruby_version);
+    rails
+      .arg(format!("_{}_", rails_version))
+      .args([
+        "new",
+        "cookie-monster",
+      ])
+      .args(Self::rails_new_flags())
+      .current_dir(version_directory);
+    Self::run(rails).await?;
+
+    let gemfile = app_directory.join("Gemfile");
+    let contents = std::fs::read_to_string(&gemfile).map_err(|err| err.to_string())?;
+    let mut contents = contents.replace(r#"gem "rails", "~> "#, r#"gem "rails", ""#);
+    // The :message_pack cookies serializer (Rails 7.1+) needs the msgpack gem.
+    contents.push_str("\ngem \"msgpack\"\n");
+    std::fs::write(&gemfile, contents).map_err(|err| err.to_string())?;
+    let production = app_directory.join("config/environments/production.rb");
+    let contents = std::fs::read_to_string(&production).map_err(|err| err.to_string())?;
+    let contents = contents.replace("config.force_ssl = true", "# config.force_ssl = true");
+    std::fs::write(&production, contents).map_err(|err| err.to_string())?;
+
+    let mut bundle = Self::command("bundle", ruby_version);
+    bundle.arg("install").current_dir(&app_directory);
+    Self::run(bundle).await?;
+
+    std::fs::write(
+      app_directory.join("app/controllers/monsters_controller.rb"),
+      CONTROLLER,
+    )
+    .map_err(|err| err.to_string())?;
+    std::fs::write(app_directory.join("config/routes.rb"), ROUTES)
+      .map_err(|err| err.to_string())?;
+    // The marker records the Ruby the app was built with, to boot it with the same.
+    std::fs::write(app_directory.join(BUILT_MARKER), ruby_version).map_err(|err| err.to_string())
+  }
+
+  /// Native counterpart of `rails-7.0.x.patch`: newer Rubies need `logger` required.
+  async fn patch_rails_7_0(&self, ruby_version: &str, rails_version: &str) -> Result<(), String> {
+    let mut ruby = Self::command("ruby", ruby_version);
+    ruby.args([
+      "-e",
+      &format!(
+        r#"print Gem::Specification.find_by_name("activesupport", "{}").gem_dir"#,
+        rails_version
+      ),
+    ]);
+    let output = ruby.output().await.map_err(|err| err.to_string())?;
+    let gem_directory = String::from_utf8_lossy(&output.stdout).to_string();
+    let path = Path::new(&gem_directory).join("lib/active_support/logger_thread_safe_level.rb");
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+      debug!("Nothing to patch for Rails v{}", rails_version);
+      return Ok(());
+    };
+    let patched = contents.replace(
+      "require \"active_support/core_ext/module/attribute_accessors\"\nrequire \"concurrent\"\nrequire \"fiber\"\n",
+      "require \"logger\"\n\n\n",
+    );
+    std::fs::write(&path, patched).map_err(|err| err.to_string())
+  }
+
+  /// Copies an app, without its temporary files and logs.
+  fn copy_app(from: &Path, to: &Path) -> std::io::Result<()> {
+    Self::copy_directory(from, to, &["tmp", "log"])
+  }
+
+  fn copy_directory(from: &Path, to: &Path, skipped: &[&str]) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+      let entry = entry?;
+      let name = entry.file_name();
+      if skipped.iter().any(|skipped| name == *skipped) {
+        continue;
+      }
+      if entry.file_type()?.is_dir() {
+        Self::copy_directory(&entry.path(), &to.join(&name), &[])?;
+      } else {
+        std::fs::copy(entry.path(), to.join(&name))?;
+      }
+    }
+    Ok(())
+  }
+
+  fn free_port() -> Result<u16, String> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").map_err(|err| err.to_string())?;
+    let address = listener.local_addr().map_err(|err| err.to_string())?;
+    Ok(address.port())
+  }
+}
+
+#[async_trait]
+impl ContainerRuntime for NativeRuntime {
+  async fn list_images(&self) -> Result<Vec<String>, String> {
+    let Ok(entries) = std::fs::read_dir(&self.root) else {
+      return Ok(vec![]);
+    };
+    Ok(
+      entries
+        .flatten()
+        .filter(|entry| {
+          entry
+            .path()
+            .join("cookie-monster")
+            .join(BUILT_MARKER)
+            .exists()
+        })
+        .map(|entry| {
+          format!(
+            "rails-cookies-everywhere:{}",
+            entry.file_name().to_string_lossy()
+          )
+        })
+        .collect(),
+    )
+  }
+
+  async fn build_image(&self, build: &ImageBuild) -> Result<(), String> {
+    let arg = |name: &str| build.args.get(name).cloned().unwrap_or_default();
+    match build.args.get("RAILS_VERSION_TAG") {
+      Some(rails_version) => {
+        self
+          .build_version(&arg("BASE_IMAGE_TAG"), rails_version, &arg("RAILS_PATCH"))
+          .await
+      }
+      None => self.build_base(&arg("BASE_IMAGE_TAG")).await,
+    }
+  }
+
+  async fn create_container(&self, spec: &ContainerSpec) -> Result<String, String> {
+    let Some(rails_version) = spec.image.strip_prefix(VERSION_IMAGE_PREFIX) else {
+      return Err(format!("Not a Rails version image: {}", spec.image));
+    };
+    if !self
+      .app_directory(rails_version)
+      .join(BUILT_MARKER)
+      .exists()
+    {
+      return Err(format!("No such image: {}", spec.image));
+    }
+
+    let id = format!("native-{}", self.next_id.fetch_add(1, Ordering::SeqCst));
+    let directory = if spec.files.is_empty() {
+      self.app_directory(rails_version)
+    } else {
+      let directory = self.root.join(&id);
+      Self::copy_app(&self.app_directory(rails_version), &directory)
+        .map_err(|err| err.to_string())?;
+      for (path, contents) in &spec.files {
+        std::fs::write(directory.join(path), contents).map_err(|err| err.to_string())?;
+      }
+      directory
+    };
+    let app = NativeApp {
+      spec: spec.clone(),
+      rails_version: rails_version.to_owned(),
+      directory,
+      port: Self::free_port()?,
+      log_path: self.root.join(format!("{}.log", id)),
+      server: None,
+      exit_code: None,
+    };
+    self.apps.lock().unwrap().insert(id.clone(), app);
+    Ok(id)
+  }
+
+  async fn start_container(&self, container_id: &str) -> Result<(), String> {
+    let mut apps = self.apps.lock().unwrap();
+    let Some(app) = apps.get_mut(container_id) else {
+      return Err(format!("No such container: {}", container_id));
+    };
+    let app_directory = app.directory.clone();
+    let ruby_version =
+      std::fs::read_to_string(app_directory.join(BUILT_MARKER)).map_err(|err| err.to_string())?;
+
+    let log = File::create(&app.log_path).map_err(|err| err.to_string())?;
+    let log_err = log.try_clone().map_err(|err| err.to_string())?;
+    let mut server = Self::command("bin/rails", &ruby_version);
+    server
+      .args([
+        "server",
+        "-b",
+        "127.0.0.1",
+        "-p",
+        &app.port.to_string(),
+      ])
+      .arg("--pid")
+      .arg(app.log_path.with_extension("pid"))
+      .env("RAILS_ENV", "production")
+      .current_dir(&app_directory)
+      .stdin(Stdio::null())
+      .stdout(log)
+      .stderr(log_err)
+      .kill_on_drop(true);
+    for env in &app.spec.env {
+      match env.split_once('=') {
+        Some((key, value)) => server.env(key, value),
+        // Unset, as Docker does
+        None => server.env_remove(env),
+      };
+    }
+    app.server = Some(server.spawn().map_err(|err| err.to_string())?);
+    Ok(())
+  }
+
+  async fn inspect_container(&self, container_id: &str) -> Result<ContainerState, String> {
+    let mut apps = self.apps.lock().unwrap();
+    let Some(app) = apps.get_mut(container_id) else {
+      return Err(format!("No such container: {}", container_id));
+    };
+    if let Some(server) = app.server.as_mut() {
+      if let Ok(Some(status)) = server.try_wait() {
+        app.exit_code = Some(status.code().unwrap_or(-1).into());
+        app.server = None;
+      }
+    }
+    Ok(ContainerState {
+      running: app.server.is_some(),
+      exit_code: app.exit_code.unwrap_or_default(),
+      address: Some(format!("localhost:{}", app.port)),
+    })
+  }
+
+  async fn container_logs(&self, container_id: &str) -> Result<Vec<String>, String> {
+    let log_path = match self.apps.lock().unwrap().get(container_id) {
+      Some(app) => app.log_path.clone(),
+      None => return Err(format!("No such container: {}", container_id)),
+    };
+    let logs = std::fs::read_to_string(log_path).unwrap_or_default();
+    Ok(logs.lines().map(str::to_owned).collect())
+  }
+
+  async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+    let Some(app) = self.apps.lock().unwrap().remove(container_id) else {
+      return Err(format!("No such container: {}", container_id));
+    };
+    if let Some(mut server) = app.server {
+      server.start_kill().map_err(|err| err.to_string())?;
+    }
+    let _ = std::fs::remove_file(&app.log_path);
+    if app.directory != self.app_directory(&app.rails_version) {
+      let _ = std::fs::remove_dir_all(&app.directory);
+    }
+    Ok(())
+  }
+
+  async fn labelled_containers(&self, label: &str) -> Result<Vec<(String, String)>, String> {
+    Ok(
+      self
+        .apps
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(id, app)| {
+          let value = app.spec.labels.get(label)?;
+          Some((id.clone(), value.clone()))
+        })
+        .collect(),
+    )
+  }
+
+  // Servers bind to the loopback interface, there is no network to isolate,
+  // nor limits to apply to the processes.
+  async fn create_network(
+    &self,
+    _name: &str,
+    _labels: &HashMap<String, String>,
+  ) -> Result<(), String> {
+    Ok(())
+  }
+
+  async fn labelled_networks(&self, _label: &str) -> Result<Vec<(String, String)>, String> {
+    Ok(Vec::new())
+  }
+
+  async fn remove_network(&self, _name: &str) -> Result<(), String> {
+    Ok(())
+  }
+}
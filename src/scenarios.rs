@@ -0,0 +1,631 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Where the generated controller is written, relative to the app root.
+pub const CONTROLLER_PATH: &str = "app/controllers/monsters_controller.rb";
+/// Where the generated routes are written, relative to the app root.
+pub const ROUTES_PATH: &str = "config/routes.rb";
+/// The default name of the session cookie of the `cookie-monster` app.
+pub const SESSION_COOKIE: &str = "_cookie_monster_session";
+
+/// The route reading the cookies of every scenario from the request.
+pub const READ_ROUTE: &str = "/read";
+
+/// The route reading the cookie named by its `name` parameter through the
+/// signed and encrypted jars, and the session.
+pub const ACCEPT_ROUTE: &str = "/accept";
+
+/// The route reading an encrypted file sent in [`ENCRYPTED_FILE_HEADER`], with
+/// the key of [`ENCRYPTED_FILE_KEY_HEADER`], and writing one with that key.
+pub const ENCRYPTED_FILE_ROUTE: &str = "/encrypted_file";
+/// The request header holding the encrypted file for Rails to read.
+pub const ENCRYPTED_FILE_HEADER: &str = "X-Encrypted-File";
+/// The request header holding the key of the encrypted files.
+pub const ENCRYPTED_FILE_KEY_HEADER: &str = "X-Encrypted-File-Key";
+
+/// The cookie jars a scenario can store its payload in.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Jar {
+  /// `cookies[name]`
+  Plain,
+  /// `cookies.signed[name]`
+  Signed,
+  /// `cookies.encrypted[name]`
+  Encrypted,
+  /// `cookies.permanent[name]`
+  Permanent,
+  /// `cookies.permanent.signed[name]`
+  PermanentSigned,
+  /// `cookies.permanent.encrypted[name]`
+  PermanentEncrypted,
+  /// `session[name]`, stored in the session cookie
+  Session,
+}
+
+impl FromStr for Jar {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    match name {
+      "plain" => Ok(Jar::Plain),
+      "signed" => Ok(Jar::Signed),
+      "encrypted" => Ok(Jar::Encrypted),
+      "permanent" => Ok(Jar::Permanent),
+      "permanent_signed" => Ok(Jar::PermanentSigned),
+      "permanent_encrypted" => Ok(Jar::PermanentEncrypted),
+      "session" => Ok(Jar::Session),
+      _ => Err(format!("Unknown cookie jar: {}", name)),
+    }
+  }
+}
+
+impl Jar {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Jar::Plain => "plain",
+      Jar::Signed => "signed",
+      Jar::Encrypted => "encrypted",
+      Jar::Permanent => "permanent",
+      Jar::PermanentSigned => "permanent_signed",
+      Jar::PermanentEncrypted => "permanent_encrypted",
+      Jar::Session => "session",
+    }
+  }
+
+  pub fn is_signed(&self) -> bool {
+    matches!(self, Jar::Signed | Jar::PermanentSigned)
+  }
+
+  pub fn is_encrypted(&self) -> bool {
+    matches!(self, Jar::Encrypted | Jar::PermanentEncrypted)
+  }
+
+  /// Whether the jar serializes its values, instead of storing their `to_s`.
+  pub fn is_serialized(&self) -> bool {
+    !matches!(self, Jar::Plain | Jar::Permanent)
+  }
+
+  /// The length of the longest string payload fitting in a 4KB cookie.
+  ///
+  /// Signing and encryption add an envelope and grow the value by a third
+  /// with Base64, and the session also stores its ID.
+  pub fn max_payload(&self) -> usize {
+    match self {
+      Jar::Plain | Jar::Permanent => 4000,
+      Jar::Signed | Jar::Encrypted | Jar::PermanentSigned | Jar::PermanentEncrypted => 2800,
+      Jar::Session => 2600,
+    }
+  }
+
+  fn accessor(&self) -> &'static str {
+    match self {
+      Jar::Plain => "cookies",
+      Jar::Signed => "cookies.signed",
+      Jar::Encrypted => "cookies.encrypted",
+      Jar::Permanent => "cookies.permanent",
+      Jar::PermanentSigned => "cookies.permanent.signed",
+      Jar::PermanentEncrypted => "cookies.permanent.encrypted",
+      Jar::Session => "session",
+    }
+  }
+}
+
+/// The options a cookie is set with, Rails defaults when missing.
+///
+/// Ignored for the session jar, whose cookie is configured by the session store.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookieOptions {
+  pub path: Option<String>,
+  pub domain: Option<String>,
+  pub secure: Option<bool>,
+  pub httponly: Option<bool>,
+  /// One of `lax`, `strict` or `none`
+  pub same_site: Option<String>,
+  /// Seconds the cookie lives for, as `expires: <n>.seconds`
+  pub expires_in: Option<u64>,
+  /// When the cookie expires, as an ISO 8601 time, possibly in the past
+  pub expires_at: Option<String>,
+}
+
+/// A cookie for the Rails app to set.
+///
+/// * name: The cookie name, or session key, also naming the scenario's route
+/// * jar: The jar the payload is stored in
+/// * payload: The value stored, as JSON
+/// * symbolize_keys: Whether the keys of hashes in the payload are symbols
+///   rather than strings in Ruby
+/// * purpose_of: Another cookie name to sign or encrypt the cookie for, so that
+///   its purpose does not match its name and Rails rejects it
+/// * options: The cookie options
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::scenarios::{self, Jar, Scenario};
+///
+/// let scenario = Scenario::new("greeting", Jar::Signed, "hello".into());
+/// let controller = scenarios::controller(&[scenario]);
+/// assert!(controller.contains(r#"cookies.signed["greeting"] = { value: JSON.parse("\"hello\"") }"#));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scenario {
+  pub name: String,
+  pub jar: Jar,
+  pub payload: Value,
+  #[serde(default)]
+  pub symbolize_keys: bool,
+  #[serde(default)]
+  pub purpose_of: Option<String>,
+  #[serde(default)]
+  pub options: CookieOptions,
+}
+
+impl Scenario {
+  pub fn new(name: &str, jar: Jar, payload: Value) -> Self {
+    Self {
+      name: name.to_owned(),
+      jar,
+      payload,
+      symbolize_keys: false,
+      purpose_of: None,
+      options: CookieOptions::default(),
+    }
+  }
+
+  pub fn with_symbolized_keys(mut self) -> Self {
+    self.symbolize_keys = true;
+    self
+  }
+
+  pub fn with_purpose_of(mut self, cookie_name: &str) -> Self {
+    self.purpose_of = Some(cookie_name.to_owned());
+    self
+  }
+
+  pub fn with_options(mut self, options: CookieOptions) -> Self {
+    self.options = options;
+    self
+  }
+
+  /// The value decoding the cookie should yield, as JSON.
+  ///
+  /// The JSON serializer turns symbols back into strings, so this is the
+  /// payload itself. For the session jar, it is the value of the scenario's
+  /// key in the decoded session, which [`crate::CapturedCookie::decoded_for`]
+  /// picks out.
+  pub fn expected(&self) -> Value {
+    self.payload.clone()
+  }
+
+  /// The name of the cookie the scenario sets, `session_key` for the session jar.
+  pub fn cookie_name<'a>(&'a self, session_key: &'a str) -> &'a str {
+    match self.jar {
+      Jar::Session => session_key,
+      _ => &self.name,
+    }
+  }
+
+  /// Checks the scenario can be turned into Ruby.
+  pub fn validate(&self) -> Result<(), String> {
+    let valid_name = !self.name.is_empty()
+      && self
+        .name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_name {
+      return Err(format!(
+        "Scenario name must be made of letters, digits, _ and -: {:?}",
+        self.name
+      ));
+    }
+    if let Some(purpose_of) = &self.purpose_of {
+      if !self.jar.is_signed() && !self.jar.is_encrypted() {
+        return Err(format!(
+          "Scenario {} has a purpose_of, but its cookies have no purpose",
+          self.name
+        ));
+      }
+      if purpose_of == &self.name {
+        return Err(format!(
+          "Scenario {} has a purpose_of matching its own name",
+          self.name
+        ));
+      }
+    }
+    if let Some(expires_at) = &self.options.expires_at {
+      if self.options.expires_in.is_some() {
+        return Err(format!(
+          "Scenario {} has both expires_in and expires_at",
+          self.name
+        ));
+      }
+      if chrono::DateTime::parse_from_rfc3339(expires_at).is_err() {
+        return Err(format!(
+          "Scenario {} has an invalid expires_at: {}",
+          self.name, expires_at
+        ));
+      }
+    }
+    if let Some(same_site) = &self.options.same_site {
+      if ![
+        "lax",
+        "strict",
+        "none",
+      ]
+      .contains(&same_site.as_str())
+      {
+        return Err(format!(
+          "Scenario {} has an unknown same_site: {}",
+          self.name, same_site
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  /// The Ruby statement setting the cookie.
+  fn statement(&self) -> String {
+    let name = self.purpose_of.as_ref().unwrap_or(&self.name);
+    let target = format!("{}[{}]", self.jar.accessor(), ruby_string(name));
+    let value = ruby_value(&self.payload, self.symbolize_keys);
+    if self.jar == Jar::Session {
+      return format!("{} = {}", target, value);
+    }
+
+    let mut options = vec![format!(
+      "value: {}",
+      value
+    )];
+    if let Some(path) = &self.options.path {
+      options.push(format!("path: {}", ruby_string(path)));
+    }
+    if let Some(domain) = &self.options.domain {
+      options.push(format!("domain: {}", ruby_string(domain)));
+    }
+    if let Some(secure) = self.options.secure {
+      options.push(format!("secure: {}", secure));
+    }
+    if let Some(httponly) = self.options.httponly {
+      options.push(format!("httponly: {}", httponly));
+    }
+    if let Some(same_site) = &self.options.same_site {
+      options.push(format!("same_site: :{}", same_site));
+    }
+    if let Some(expires_in) = self.options.expires_in {
+      options.push(format!("expires: {}.seconds", expires_in));
+    }
+    if let Some(expires_at) = &self.options.expires_at {
+      options.push(format!(
+        "expires: Time.iso8601({})",
+        ruby_string(expires_at)
+      ));
+    }
+    let statement = format!("{} = {{ {} }}", target, options.join(", "));
+    match &self.purpose_of {
+      // The parent jar holds the signed or encrypted value, which is set as is
+      // under the scenario's name.
+      Some(purpose_of) => format!(
+        "{}\n    cookies[{}] = {{ value: cookies[{}] }}",
+        statement,
+        ruby_string(&self.name),
+        ruby_string(purpose_of)
+      ),
+      None => statement,
+    }
+  }
+}
+
+/// The scenarios of the original app: the canary in an encrypted cookie and in the session.
+pub fn default_scenarios(canary: &str) -> Vec<Scenario> {
+  vec![
+    Scenario::new("encrypted", Jar::Encrypted, canary.into()),
+    Scenario::new("session", Jar::Session, canary.into()),
+  ]
+}
+
+/// Payloads of every JSON type, with the edge cases of each, by kind.
+///
+/// The large payload is sized to fit in a cookie of `jar`.
+pub fn typed_payloads(jar: Jar) -> Vec<(&'static str, Value)> {
+  vec![
+    ("string", json!("correct-horse-battery-staple")),
+    ("unicode", json!("Crème brûlée, 饼干 & 🍪")),
+    ("empty-string", json!("")),
+    ("integer", json!(42)),
+    ("negative-integer", json!(-7)),
+    // Not representable as a double, decoders must not go through floats.
+    ("big-integer", json!(9007199254740993u64)),
+    ("float", json!(1.25)),
+    ("negative-float", json!(-0.5)),
+    ("true", json!(true)),
+    ("false", json!(false)),
+    ("nil", Value::Null),
+    (
+      "array",
+      json!([
+        1,
+        "two",
+        3.0,
+        null,
+        [false]
+      ]),
+    ),
+    ("empty-array", json!([])),
+    (
+      "hash",
+      json!({"user": {"id": 1, "roles": ["admin", "editor"], "active": true}}),
+    ),
+    ("empty-hash", json!({})),
+    ("large", json!("x".repeat(jar.max_payload()))),
+  ]
+}
+
+/// A scenario per typed payload, stored in `jar` and named `<jar>-<kind>`.
+///
+/// Hashes are also stored with symbol keys, as Rails apps commonly do. Plain
+/// and permanent cookies store the `to_s` of their value, so are not typed.
+///
+/// # Examples
+/// ```
+/// use rails_cookies_monster::scenarios::{self, Jar};
+///
+/// let suite = scenarios::payload_suite(Jar::Encrypted).unwrap();
+/// let nil = suite.iter().find(|scenario| scenario.name == "encrypted-nil").unwrap();
+/// assert_eq!(nil.expected(), serde_json::Value::Null);
+/// assert!(scenarios::payload_suite(Jar::Plain).is_err());
+/// ```
+pub fn payload_suite(jar: Jar) -> Result<Vec<Scenario>, String> {
+  if !jar.is_serialized() {
+    return Err(format!(
+      "The {} jar stores strings, not typed payloads",
+      jar.name()
+    ));
+  }
+  let mut suite = vec![];
+  for (kind, payload) in typed_payloads(jar) {
+    let name = format!("{}-{}", jar.name(), kind);
+    suite.push(Scenario::new(&name, jar, payload.clone()));
+    if payload.is_object() {
+      let symbolized = format!("{}-symbol-keys", name);
+      suite.push(Scenario::new(&symbolized, jar, payload).with_symbolized_keys());
+    }
+  }
+  Ok(suite)
+}
+
+/// Scenarios expiring in an hour, having expired, and permanent, for each
+/// jar embedding its expiry in the message.
+pub fn expiry_suite(canary: &str) -> Vec<Scenario> {
+  let mut suite = vec![];
+  for jar in [
+    Jar::Signed,
+    Jar::Encrypted,
+  ] {
+    let expiring = CookieOptions {
+      expires_in: Some(3600),
+      ..CookieOptions::default()
+    };
+    let expired = CookieOptions {
+      expires_at: Some("2000-01-01T00:00:00Z".to_string()),
+      ..CookieOptions::default()
+    };
+    let name = |kind: &str| format!("{}-{}", jar.name(), kind);
+    suite.push(Scenario::new(&name("expiring"), jar, canary.into()).with_options(expiring));
+    suite.push(Scenario::new(&name("expired"), jar, canary.into()).with_options(expired));
+  }
+  for jar in [
+    Jar::Permanent,
+    Jar::PermanentSigned,
+    Jar::PermanentEncrypted,
+  ] {
+    suite.push(Scenario::new(jar.name(), jar, canary.into()));
+  }
+  suite
+}
+
+/// Signed and encrypted cookies whose purpose is another cookie name.
+///
+/// Rails rejects them, decoders must too.
+pub fn purpose_suite(canary: &str) -> Vec<Scenario> {
+  [
+    Jar::Signed,
+    Jar::Encrypted,
+  ]
+  .into_iter()
+  .map(|jar| {
+    let name = format!("{}-purpose-mismatch", jar.name());
+    let purpose_of = format!("{}-purpose-origin", jar.name());
+    Scenario::new(&name, jar, canary.into()).with_purpose_of(&purpose_of)
+  })
+  .collect()
+}
+
+/// Loads scenarios from a JSON file holding a list of [`Scenario`].
+pub fn load(path: &str) -> Result<Vec<Scenario>, String> {
+  let contents = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+  let scenarios: Vec<Scenario> =
+    serde_json::from_str(&contents).map_err(|err| format!("{}: {}", path, err))?;
+  for (index, scenario) in scenarios.iter().enumerate() {
+    scenario.validate()?;
+    if scenarios[..index]
+      .iter()
+      .any(|other| other.name == scenario.name)
+    {
+      return Err(format!("Duplicate scenario: {}", scenario.name));
+    }
+  }
+  Ok(scenarios)
+}
+
+/// The path of the route setting only the cookie of `scenario`.
+pub fn route(scenario: &Scenario) -> String {
+  format!("/scenarios/{}", scenario.name)
+}
+
+/// Generates the controller of the `cookie-monster` app.
+///
+/// `/` sets the cookies of every scenario at once, and each scenario also has
+/// an action of its own. [`READ_ROUTE`] reads the cookie of every scenario
+/// through its jar, as Rails decodes it, and renders them under `cookies`.
+/// [`ENCRYPTED_FILE_ROUTE`] renders what Rails read from the encrypted file it
+/// was sent, and a file it encrypted with the same key, under `encrypted_file`.
+/// [`ACCEPT_ROUTE`] renders what the signed and encrypted jars read from the
+/// cookie it is given, and the session, under `accepted`.
+/// Every action renders what the app knows about itself.
+pub fn controller(scenarios: &[Scenario]) -> String {
+  let mut ruby =
+    String::from("require \"tmpdir\"\n\nclass MonstersController < ActionController::Base\n");
+  ruby.push_str("  def cookies_monster\n");
+  for index in 0..scenarios.len() {
+    ruby.push_str(&format!("    set_scenario_{}\n", index));
+  }
+  ruby.push_str("    report\n  end\n");
+
+  ruby.push_str("\n  def read_cookies\n    report(cookies: {\n");
+  for scenario in scenarios {
+    let name = ruby_string(&scenario.name);
+    ruby.push_str(&format!(
+      "      {} => {}[{}],\n",
+      name,
+      scenario.jar.accessor(),
+      name
+    ));
+  }
+  ruby.push_str("    })\n  end\n");
+  ruby.push_str(
+    r#"
+  # Reads a cookie sent by rails-cookies-monster, as each jar decodes it.
+  def accept_cookie
+    name = params.require(:name)
+    report(accepted: {
+      signed: cookies.signed[name],
+      encrypted: cookies.encrypted[name],
+      session: session.to_hash,
+    })
+  end
+"#,
+  );
+  ruby.push_str(&format!(
+    r#"
+  # Reads the file encrypted by rails-cookies-monster, and encrypts the canary
+  # with the same key.
+  def encrypted_file
+    Dir.mktmpdir do |dir|
+      key_path = File.join(dir, "monster.key")
+      File.write(key_path, request.headers[{}])
+      File.write(File.join(dir, "read.yml.enc"), request.headers[{}])
+      file = lambda do |name|
+        ActiveSupport::EncryptedFile.new(
+          content_path: File.join(dir, name),
+          key_path: key_path,
+          env_key: "RAILS_COOKIES_MONSTER_KEY",
+          raise_if_missing_key: true,
+        )
+      end
+      written = file.call("written.yml.enc")
+      written.write("canary: #{{ENV["CANARY_VALUE"]}}\n")
+      report(encrypted_file: {{
+        read: (file.call("read.yml.enc").read rescue nil),
+        written: File.read(written.content_path),
+      }})
+    end
+  end
+"#,
+    ruby_string(ENCRYPTED_FILE_KEY_HEADER),
+    ruby_string(ENCRYPTED_FILE_HEADER)
+  ));
+
+  for (index, scenario) in scenarios.iter().enumerate() {
+    ruby.push_str(&format!(
+      "\n  # {}\n  def scenario_{}\n    set_scenario_{}\n    report\n  end\n",
+      scenario.name, index, index
+    ));
+  }
+
+  ruby.push_str("\n  private\n");
+  for (index, scenario) in scenarios.iter().enumerate() {
+    ruby.push_str(&format!(
+      "\n  def set_scenario_{}\n    {}\n  end\n",
+      index,
+      scenario.statement()
+    ));
+  }
+  ruby.push_str(
+    r#"
+  def report(**extra)
+    render json: {
+      version: Rails::VERSION::STRING,
+      environment: Rails.env,
+      # Generated in development and test, whatever SECRET_KEY_BASE says.
+      secret_key_base: Rails.application.secret_key_base,
+    }.merge(credentials).merge(extra)
+  end
+
+  # The encrypted credentials the secret is read from, without SECRET_KEY_BASE.
+  def credentials
+    return {} if ENV.key?("SECRET_KEY_BASE")
+
+    credentials = Rails.application.credentials
+    { credentials: { master_key: credentials.key, content: File.read(credentials.content_path) } }
+  end
+end
+"#,
+  );
+  ruby
+}
+
+/// Generates the routes of the `cookie-monster` app, matching [`controller`].
+pub fn routes(scenarios: &[Scenario]) -> String {
+  let mut ruby = String::from("Rails.application.routes.draw do\n");
+  ruby.push_str("  get '/' => 'monsters#cookies_monster'\n");
+  ruby.push_str(&format!(
+    "  get '{}' => 'monsters#read_cookies'\n",
+    READ_ROUTE
+  ));
+  ruby.push_str(&format!(
+    "  get '{}' => 'monsters#accept_cookie'\n",
+    ACCEPT_ROUTE
+  ));
+  ruby.push_str(&format!(
+    "  get '{}' => 'monsters#encrypted_file'\n",
+    ENCRYPTED_FILE_ROUTE
+  ));
+  ruby.push_str(&crate::flows::routes());
+  for (index, scenario) in scenarios.iter().enumerate() {
+    ruby.push_str(&format!(
+      "  get '{}' => 'monsters#scenario_{}'\n",
+      route(scenario),
+      index
+    ));
+  }
+  ruby.push_str("end\n");
+  ruby
+}
+
+/// The generated files, with their path relative to the app root.
+pub fn files(scenarios: &[Scenario]) -> Vec<(String, String)> {
+  vec![
+    (CONTROLLER_PATH.to_string(), controller(scenarios)),
+    (ROUTES_PATH.to_string(), routes(scenarios)),
+  ]
+}
+
+/// A Ruby string literal.
+///
+/// JSON escapes are valid in double-quoted Ruby strings, only interpolation
+/// needs escaping on top.
+pub(crate) fn ruby_string(value: &str) -> String {
+  serde_json::to_string(value).unwrap().replace('#', "\\#")
+}
+
+/// A Ruby expression evaluating to `value`, parsed from JSON by Ruby itself.
+fn ruby_value(value: &Value, symbolize_keys: bool) -> String {
+  let json = ruby_string(&value.to_string());
+  match symbolize_keys {
+    true => format!("JSON.parse({}, symbolize_names: true)", json),
+    false => format!("JSON.parse({})", json),
+  }
+}